@@ -0,0 +1,20 @@
+struct Foo;
+
+impl Foo {
+    async fn inherent(&self) {
+        async {};
+    }
+}
+
+trait Bar {
+    async fn declared(&self);
+
+    async fn provided(&self) {
+        async {};
+        async {};
+    }
+}
+
+impl Bar for Foo {
+    async fn declared(&self) {}
+}
@@ -0,0 +1,15 @@
+fn collect<T>(
+    xs: impl Iterator<Item = &'static str>,
+    ys: impl IntoIterator<Item = T>,
+    f: impl FnOnce(u8) -> Box<dyn Iterator<Item = u8>>,
+) -> impl Iterator<Item = impl Clone> {
+    todo!()
+}
+
+struct Tasks {
+    pending: Vec<Box<dyn Iterator<Item = Box<dyn Send>>>>,
+}
+
+impl Tasks {
+    fn drain<I: Iterator<Item = u16>>(&mut self, _: I) {}
+}
@@ -0,0 +1,25 @@
+fn sum<I: Iterator<Item = i32> + Send + 'static>(xs: I) -> i32 {
+    xs.sum()
+}
+
+trait Container {
+    type Item: Clone;
+}
+
+struct Wrapper<T>(T);
+
+impl<T: Default> Wrapper<T>
+where
+    T: Sync,
+{
+    fn map<U: From<T>>(self) -> U {
+        todo!()
+    }
+}
+
+trait Source<T: Copy> {
+    type Stream<'a>: Iterator<Item: Ord>
+    where
+        Self: 'a;
+    fn pull<R: Read>(&self) -> R;
+}
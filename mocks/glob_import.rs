@@ -0,0 +1,3 @@
+use lib::*;
+
+fn f<T: Foo>(x: T) {}
@@ -0,0 +1,27 @@
+struct Callbacks {
+    on_event: Vec<Box<dyn Fn()>>,
+    nested: Option<Box<dyn Iterator<Item = &'static dyn Debug>>>,
+}
+
+type Shared = Rc<dyn Debug>;
+static HOOK: Option<&dyn Sync> = None;
+
+impl dyn Debug {}
+
+fn main() {
+    let x: &dyn Debug = &1;
+    let _ = x as &dyn Debug;
+}
+
+use std::fmt::Debug;
+
+struct Handler;
+
+impl Handler {
+    const DEFAULT: &'static dyn Sync = &();
+
+    fn handle(&self, event: &dyn Debug, _: impl Fn()) -> Box<dyn Debug> {
+        let boxed: Box<dyn Debug> = Box::new(event);
+        boxed as Box<dyn Debug>
+    }
+}
@@ -0,0 +1,21 @@
+use std::io::{self, Write as IoWrite};
+use futures::stream::Stream;
+
+mod ops {
+    pub trait Add {}
+
+    impl Add for u8 {}
+    impl std::ops::Add for super::Mock {}
+
+    fn add(x: impl super::ops::Add) {}
+}
+
+struct Mock;
+
+fn copy(from: impl io::Read, to: &mut dyn IoWrite) -> impl Stream {
+    use ops::Add;
+    let x: &dyn Add = &1u8;
+    todo!()
+}
+
+fn add(x: impl ops::Add) {}
@@ -0,0 +1,20 @@
+struct Foo;
+
+impl Foo {
+    unsafe fn inherent(&self) {
+        unsafe {}
+    }
+}
+
+unsafe trait Bar {
+    unsafe fn declared(&self);
+
+    unsafe fn provided(&self) {
+        unsafe {}
+        unsafe {}
+    }
+}
+
+unsafe impl Bar for Foo {
+    unsafe fn declared(&self) {}
+}
@@ -264,7 +264,7 @@ fn download_github_repo(
     if source_path.exists() {
         Ok(false)
     } else {
-        unpack_tar_gz(&url, &source_path.parent().unwrap())?;
+        unpack_tar_gz(&url, source_path.parent().unwrap())?;
         Ok(true)
     }
 }
@@ -283,14 +283,14 @@ fn clone_github_repo(
     let mut remote;
     let to_checkout = 'fetch_repo: {
         if source_path.exists() {
-            repo = Repository::open(&source_path)?;
+            repo = Repository::open(source_path)?;
             if let Ok(to_checkout) = repo.find_object(shaid, shaty) {
                 // has the correct sha already fetched
                 break 'fetch_repo to_checkout;
             }
             remote = repo.find_remote("origin")?;
         } else {
-            repo = Repository::init(&source_path)?;
+            repo = Repository::init(source_path)?;
             remote = repo.remote("origin", &url)?;
         }
         remote.fetch(&[branch], None, None)?;
@@ -320,7 +320,7 @@ fn download_github(args: &Args, output: &Path) -> Result<(), Error> {
     repos.truncate(args.github_count);
 
     let source_dir = output.join("source");
-    let old_source_dir = output.join(format!("source_{}", format_rfc3339_seconds(now.into())));
+    let old_source_dir = output.join(format!("source_{}", format_rfc3339_seconds(now)));
     fs::rename(&source_dir, &old_source_dir)?;
     fs::create_dir(&source_dir)?;
     let bar = ProgressBar::new(repos.len() as u64);
@@ -348,7 +348,7 @@ fn download_github(args: &Args, output: &Path) -> Result<(), Error> {
                 if alt_path.exists() {
                     fs::rename(&alt_path, &source_path)?;
                 } else if args.clone_repos {
-                    let alt_path = old_source_dir.join(&format!("{name}-git"));
+                    let alt_path = old_source_dir.join(format!("{name}-git"));
                     if alt_path.exists() {
                         fs::rename(&alt_path, &source_path)?;
                     }
@@ -0,0 +1,142 @@
+use crate::db::{Database, ToValue, Value};
+use crate::roles::CodeRole;
+use anyhow::{bail, Error};
+use git2::{ObjectType, Oid};
+use std::collections::HashMap;
+use std::mem;
+use uuid::Uuid;
+
+type Rows = Vec<(String, Vec<(String, Value)>)>;
+type Key = (String, CodeRole, Oid);
+
+/// Rows emitted for each file of the previous snapshot, keyed by path, role and
+/// git blob id, so that unchanged files don't have to be parsed again.
+///
+/// The cache lives for a single walk over one repository, so every entry was
+/// produced by the runners of this build.
+#[derive(Default)]
+pub struct ParseCache {
+    previous: HashMap<Key, Rows>,
+    current: HashMap<Key, Rows>,
+}
+
+impl ParseCache {
+    pub fn key(file_name: &str, role: CodeRole, source: &str) -> Key {
+        let oid = Oid::hash_object(ObjectType::Blob, source.as_bytes()).unwrap();
+        (file_name.to_string(), role, oid)
+    }
+
+    /// Insert the cached rows for `key` under `version_id`, if there are any.
+    pub fn replay(
+        &mut self,
+        key: &Key,
+        db: &mut dyn Database,
+        version_id: Uuid,
+    ) -> Result<bool, Error> {
+        let Some(rows) = self.previous.remove(key) else {
+            return Ok(false);
+        };
+        for (table, row) in &rows {
+            let row: Vec<_> = row
+                .iter()
+                .map(|(c, v)| match c.as_str() {
+                    "version_id" => (c.as_str(), &version_id as &dyn ToValue),
+                    _ => (c.as_str(), v as &dyn ToValue),
+                })
+                .collect();
+            db.insert(table, &row)?;
+        }
+        self.current.insert(key.clone(), rows);
+        Ok(true)
+    }
+
+    pub fn insert(&mut self, key: Key, rows: Rows) {
+        self.current.insert(key, rows);
+    }
+
+    /// Forget files which did not appear in the snapshot that just finished.
+    pub fn finish_snapshot(&mut self) {
+        self.previous = mem::take(&mut self.current);
+    }
+}
+
+/// Keeps the rows inserted while analyzing a file, so that they only reach the
+/// transaction once every runner finished with it.
+#[derive(Default)]
+pub struct Recorder {
+    pub rows: Rows,
+}
+
+impl Recorder {
+    pub fn write(&self, db: &mut dyn Database) -> Result<(), Error> {
+        for (table, row) in &self.rows {
+            let row: Vec<_> = row
+                .iter()
+                .map(|(c, v)| (c.as_str(), v as &dyn ToValue))
+                .collect();
+            db.insert(table, &row)?;
+        }
+        Ok(())
+    }
+}
+
+impl Database for Recorder {
+    fn batch_execute(&mut self, query: &str) -> Result<(), Error> {
+        bail!("only inserts can be recorded: {query}")
+    }
+
+    fn execute(&mut self, query: &str, _params: &[&dyn ToValue]) -> Result<(), Error> {
+        bail!("only inserts can be recorded: {query}")
+    }
+
+    fn query(&mut self, query: &str, _params: &[&dyn ToValue]) -> Result<Vec<Vec<Value>>, Error> {
+        bail!("only inserts can be recorded: {query}")
+    }
+
+    fn create_enum(&mut self, name: &str, _variants: &[&str]) -> Result<(), Error> {
+        bail!("only inserts can be recorded: {name}")
+    }
+
+    fn extend_enum(&mut self, name: &str, _variants: &[&str]) -> Result<(), Error> {
+        bail!("only inserts can be recorded: {name}")
+    }
+
+    fn insert(&mut self, table: &str, row: &[(&str, &dyn ToValue)]) -> Result<(), Error> {
+        let row = row
+            .iter()
+            .map(|(c, v)| (c.to_string(), v.to_value()))
+            .collect();
+        self.rows.push((table.to_string(), row));
+        Ok(())
+    }
+}
+
+#[test]
+fn test_replay() {
+    let mut db = crate::db::MemoryDatabase::default();
+    let mut cache = ParseCache::default();
+    let key = ParseCache::key("src/lib.rs", CodeRole::Library, "fn main() {}");
+
+    let first = Uuid::new_v4();
+    let mut recorder = Recorder::default();
+    assert!(!cache.replay(&key, &mut db, first).unwrap());
+    recorder
+        .insert("closures", &[("line_number", &1), ("version_id", &first)])
+        .unwrap();
+    recorder.write(&mut db).unwrap();
+    assert!(db.contains("closures", &[("version_id", &first)]));
+    cache.insert(key.clone(), recorder.rows);
+    cache.finish_snapshot();
+
+    let second = Uuid::new_v4();
+    assert!(cache.replay(&key, &mut db, second).unwrap());
+    assert!(db.contains(
+        "closures",
+        &[("line_number", &1), ("version_id", &second)]
+    ));
+    cache.finish_snapshot();
+
+    // Files are forgotten once a snapshot goes by without them
+    cache.finish_snapshot();
+    assert!(!cache.replay(&key, &mut db, Uuid::new_v4()).unwrap());
+}
@@ -0,0 +1,825 @@
+use crate::roles::ROLE_COLUMN;
+use anyhow::{bail, Context, Error};
+use postgres::types::{IsNull, ToSql, Type};
+use postgres::NoTls;
+use rusqlite::types::ToSqlOutput;
+use rusqlite::TransactionBehavior;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+mod parquet_dir;
+mod pg_copy;
+
+pub use parquet_dir::{ParquetDir, ParquetTransaction};
+pub use pg_copy::CopyTransaction;
+
+/// A single SQL parameter, independent of the storage backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Text(String),
+    Uuid(Uuid),
+    /// The label of a `sql_enum!` variant
+    Enum(&'static str),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => Ok(()),
+            Value::Bool(v) => write!(f, "{v}"),
+            Value::Int(v) => write!(f, "{v}"),
+            Value::Text(v) => write!(f, "{v}"),
+            Value::Uuid(v) => write!(f, "{v}"),
+            Value::Enum(v) => write!(f, "{v}"),
+        }
+    }
+}
+
+pub trait ToValue {
+    fn to_value(&self) -> Value;
+}
+
+impl ToValue for Value {
+    fn to_value(&self) -> Value {
+        self.clone()
+    }
+}
+
+impl ToValue for bool {
+    fn to_value(&self) -> Value {
+        Value::Bool(*self)
+    }
+}
+
+impl ToValue for i32 {
+    fn to_value(&self) -> Value {
+        Value::Int((*self).into())
+    }
+}
+
+impl ToValue for usize {
+    fn to_value(&self) -> Value {
+        // Counts and byte offsets can't get anywhere near `i64::MAX`
+        Value::Int(i64::try_from(*self).expect("usize overflows i64"))
+    }
+}
+
+impl ToValue for str {
+    fn to_value(&self) -> Value {
+        Value::Text(self.to_owned())
+    }
+}
+
+impl ToValue for String {
+    fn to_value(&self) -> Value {
+        Value::Text(self.clone())
+    }
+}
+
+impl ToValue for Uuid {
+    fn to_value(&self) -> Value {
+        Value::Uuid(*self)
+    }
+}
+
+impl<T: ToValue> ToValue for Option<T> {
+    fn to_value(&self) -> Value {
+        match self {
+            Some(v) => v.to_value(),
+            None => Value::Null,
+        }
+    }
+}
+
+impl<T: ToValue + ?Sized> ToValue for &T {
+    fn to_value(&self) -> Value {
+        (**self).to_value()
+    }
+}
+
+/// The column type used to store a [`ToValue`].
+pub trait SqlType {
+    const SQL_TYPE: &'static str;
+}
+
+impl SqlType for bool {
+    const SQL_TYPE: &'static str = "BOOLEAN";
+}
+
+impl SqlType for i32 {
+    const SQL_TYPE: &'static str = "INT";
+}
+
+impl SqlType for usize {
+    const SQL_TYPE: &'static str = "INT";
+}
+
+impl SqlType for String {
+    const SQL_TYPE: &'static str = "TEXT";
+}
+
+impl SqlType for Uuid {
+    const SQL_TYPE: &'static str = "UUID";
+}
+
+impl<T: SqlType> SqlType for Option<T> {
+    const SQL_TYPE: &'static str = T::SQL_TYPE;
+}
+
+#[derive(Debug)]
+pub struct Column {
+    pub name: &'static str,
+    pub sql_type: &'static str,
+    /// The migration which added this column, if it wasn't there from the start
+    pub since: Option<usize>,
+    pub primary_key: bool,
+    /// The `table(column)` this column is a foreign key into
+    pub references: Option<&'static str>,
+}
+
+impl Column {
+    /// The column as written in `CREATE TABLE` and `ALTER TABLE ... ADD COLUMN`.
+    fn definition(&self) -> String {
+        let mut definition = format!("{} {}", self.name, self.sql_type);
+        if self.primary_key {
+            definition.push_str(" PRIMARY KEY");
+        }
+        if let Some(target) = self.references {
+            definition.push_str(&format!(" REFERENCES {target}"));
+        }
+        definition
+    }
+}
+
+/// A row of a runner's table, usually declared with `sql_row!`.
+///
+/// Every table also gets the `file_name` and `version_id` columns which the
+/// [`Logger`](crate::stats::Logger) fills in.
+pub trait SqlRow: fmt::Debug {
+    const TABLE: &'static str;
+    const COLUMNS: &'static [Column];
+
+    fn values(&self) -> Vec<&dyn ToValue>;
+
+    fn columns(&self) -> Vec<(&'static str, &dyn ToValue)> {
+        Self::COLUMNS.iter().map(|c| c.name).zip(self.values()).collect()
+    }
+}
+
+/// The columns which [`create_table`] adds to every table and the
+/// [`Logger`](crate::stats::Logger) fills in.
+pub const FILE_COLUMNS: &[Column] = &[
+    Column {
+        name: "file_name",
+        sql_type: "TEXT",
+        since: None,
+        primary_key: false,
+        references: None,
+    },
+    Column {
+        name: "version_id",
+        sql_type: "UUID",
+        since: None,
+        primary_key: false,
+        references: Some("versions(id)"),
+    },
+];
+
+/// Create the table for `R` as it was first released.
+pub fn create_table<R: SqlRow>(db: &mut dyn Database) -> Result<(), Error> {
+    let table = R::TABLE;
+    create_table_with::<R>(db, FILE_COLUMNS)?;
+    db.batch_execute(&format!(
+        "CREATE INDEX {table}_version_index ON {table}(version_id);"
+    ))
+}
+
+/// Create the table for `R` as it was first released, with only the columns
+/// of `R`, for tables which aren't about a file.
+pub fn create_row_table<R: SqlRow>(db: &mut dyn Database) -> Result<(), Error> {
+    create_table_with::<R>(db, &[])
+}
+
+fn create_table_with<R: SqlRow>(db: &mut dyn Database, extra: &[Column]) -> Result<(), Error> {
+    let columns: Vec<_> = R::COLUMNS
+        .iter()
+        .filter(|c| c.since.is_none())
+        .chain(extra)
+        .map(Column::definition)
+        .collect();
+    db.batch_execute(&format!(
+        r"CREATE TABLE {} (
+            {}
+        );",
+        R::TABLE,
+        columns.join(",\n            "),
+    ))
+}
+
+/// The columns which [`Logger::push_at`](crate::stats::Logger::push_at) fills
+/// in from the span of a row. Columns count characters from 0, as proc-macro2
+/// does, and byte ranges are half-open offsets into the file.
+pub const LOCATION_COLUMNS: &[Column] = &[
+    Column {
+        name: "start_line",
+        sql_type: "INT",
+        since: None,
+        primary_key: false,
+        references: None,
+    },
+    Column {
+        name: "start_column",
+        sql_type: "INT",
+        since: None,
+        primary_key: false,
+        references: None,
+    },
+    Column {
+        name: "end_line",
+        sql_type: "INT",
+        since: None,
+        primary_key: false,
+        references: None,
+    },
+    Column {
+        name: "end_column",
+        sql_type: "INT",
+        since: None,
+        primary_key: false,
+        references: None,
+    },
+    Column {
+        name: "start_byte",
+        sql_type: "INT",
+        since: None,
+        primary_key: false,
+        references: None,
+    },
+    Column {
+        name: "end_byte",
+        sql_type: "INT",
+        since: None,
+        primary_key: false,
+        references: None,
+    },
+    Column {
+        name: "item_path",
+        sql_type: "TEXT",
+        since: None,
+        primary_key: false,
+        references: None,
+    },
+];
+
+/// Add `column` to the existing `table`.
+pub fn add_column(db: &mut dyn Database, table: &str, column: &Column) -> Result<(), Error> {
+    db.batch_execute(&format!(
+        "ALTER TABLE {table} ADD COLUMN {};",
+        column.definition()
+    ))
+}
+
+/// Add the columns of `R` which migration `since` introduced to its table.
+pub fn add_columns<R: SqlRow>(db: &mut dyn Database, since: usize) -> Result<(), Error> {
+    for c in R::COLUMNS.iter().filter(|c| c.since == Some(since)) {
+        add_column(db, R::TABLE, c)?;
+    }
+    Ok(())
+}
+
+/// Add [`LOCATION_COLUMNS`] to an existing table.
+pub fn add_location_columns(db: &mut dyn Database, table: &str) -> Result<(), Error> {
+    for c in LOCATION_COLUMNS {
+        add_column(db, table, c)?;
+    }
+    Ok(())
+}
+
+/// A table with every column it has once all migrations ran, for backends
+/// which declare their schema up front instead of running the migrations.
+#[derive(Clone, Copy)]
+pub struct Table {
+    pub name: &'static str,
+    pub columns: fn() -> Vec<&'static Column>,
+}
+
+impl Table {
+    /// A table with exactly the columns of `R`.
+    pub const fn of<R: SqlRow>() -> Self {
+        fn columns<R: SqlRow>() -> Vec<&'static Column> {
+            R::COLUMNS.iter().collect()
+        }
+        Table {
+            name: R::TABLE,
+            columns: columns::<R>,
+        }
+    }
+
+    /// A table made by [`create_table`].
+    pub const fn created<R: SqlRow>() -> Self {
+        fn columns<R: SqlRow>() -> Vec<&'static Column> {
+            R::COLUMNS.iter().chain(FILE_COLUMNS).collect()
+        }
+        Table {
+            name: R::TABLE,
+            columns: columns::<R>,
+        }
+    }
+
+    /// A table made by [`create_table`] and extended with
+    /// [`add_location_columns`] and
+    /// [`add_role_column`](crate::roles::add_role_column).
+    pub const fn located<R: SqlRow>() -> Self {
+        fn columns<R: SqlRow>() -> Vec<&'static Column> {
+            R::COLUMNS
+                .iter()
+                .chain(FILE_COLUMNS)
+                .chain(LOCATION_COLUMNS)
+                .chain([&ROLE_COLUMN])
+                .collect()
+        }
+        Table {
+            name: R::TABLE,
+            columns: columns::<R>,
+        }
+    }
+}
+
+impl ToSql for Value {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut postgres_types::private::BytesMut,
+    ) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        match self {
+            Value::Null => Ok(IsNull::Yes),
+            Value::Bool(v) => v.to_sql_checked(ty, out),
+            // `INT` columns are 32 bit in Postgres; refuse to truncate
+            Value::Int(v) if *ty == Type::INT4 => i32::try_from(*v)?.to_sql_checked(ty, out),
+            Value::Int(v) => v.to_sql_checked(ty, out),
+            Value::Text(v) => v.to_sql_checked(ty, out),
+            Value::Uuid(v) => v.to_sql_checked(ty, out),
+            // Postgres enums use their label as the binary representation
+            Value::Enum(v) => {
+                out.extend_from_slice(v.as_bytes());
+                Ok(IsNull::No)
+            }
+        }
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+
+    postgres_types::to_sql_checked!();
+}
+
+impl rusqlite::ToSql for Value {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            Value::Null => ToSqlOutput::from(rusqlite::types::Null),
+            Value::Bool(v) => ToSqlOutput::from(*v),
+            Value::Int(v) => ToSqlOutput::from(*v),
+            Value::Text(v) => ToSqlOutput::from(v.as_str()),
+            Value::Uuid(v) => ToSqlOutput::from(v.to_string()),
+            Value::Enum(v) => ToSqlOutput::from(*v),
+        })
+    }
+}
+
+fn postgres_value(row: &postgres::Row, i: usize) -> Result<Value, Error> {
+    let ty = row.columns()[i].type_();
+    let value = match *ty {
+        Type::BOOL => row.try_get::<_, Option<bool>>(i)?.map(Value::Bool),
+        Type::INT4 => row
+            .try_get::<_, Option<i32>>(i)?
+            .map(|v| Value::Int(v.into())),
+        Type::INT8 => row.try_get::<_, Option<i64>>(i)?.map(Value::Int),
+        Type::TEXT | Type::VARCHAR => row.try_get::<_, Option<String>>(i)?.map(Value::Text),
+        Type::UUID => row.try_get::<_, Option<Uuid>>(i)?.map(Value::Uuid),
+        _ => bail!("unsupported column type {ty}"),
+    };
+    Ok(value.unwrap_or(Value::Null))
+}
+
+fn sqlite_value(row: &rusqlite::Row, i: usize) -> Result<Value, Error> {
+    use rusqlite::types::ValueRef;
+    Ok(match row.get_ref(i)? {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(v) => Value::Int(v),
+        ValueRef::Text(v) => Value::Text(String::from_utf8(v.to_vec())?),
+        v => bail!("unsupported column type {:?}", v.data_type()),
+    })
+}
+
+/// Where the results of a run are stored.
+///
+/// Queries are written in the common subset of Postgres and SQLite, using
+/// `$1`-style placeholders which SQLite numbers in order of appearance.
+pub trait Database {
+    fn batch_execute(&mut self, query: &str) -> Result<(), Error>;
+    fn execute(&mut self, query: &str, params: &[&dyn ToValue]) -> Result<(), Error>;
+    fn query(&mut self, query: &str, params: &[&dyn ToValue]) -> Result<Vec<Vec<Value>>, Error>;
+    /// Create an enum type, unless another component which shares it already
+    /// did.
+    fn create_enum(&mut self, name: &str, variants: &[&str]) -> Result<(), Error>;
+    /// Add `variants` to an enum created by an earlier migration. Fresh
+    /// databases already have them, as `create_enum` is passed every variant.
+    fn extend_enum(&mut self, name: &str, variants: &[&str]) -> Result<(), Error>;
+
+    fn insert(&mut self, table: &str, row: &[(&str, &dyn ToValue)]) -> Result<(), Error> {
+        let columns: Vec<_> = row.iter().map(|(c, _)| *c).collect();
+        let placeholders: Vec<_> = (1..=row.len()).map(|i| format!("${i}")).collect();
+        let params: Vec<_> = row.iter().map(|(_, v)| *v).collect();
+        self.execute(
+            &format!(
+                "INSERT INTO {table} ({}) VALUES ({})",
+                columns.join(", "),
+                placeholders.join(", "),
+            ),
+            &params,
+        )
+    }
+}
+
+impl Database for postgres::Transaction<'_> {
+    fn batch_execute(&mut self, query: &str) -> Result<(), Error> {
+        postgres::Transaction::batch_execute(self, query)?;
+        Ok(())
+    }
+
+    fn execute(&mut self, query: &str, params: &[&dyn ToValue]) -> Result<(), Error> {
+        let values: Vec<_> = params.iter().map(|p| p.to_value()).collect();
+        let params: Vec<_> = values.iter().map(|v| v as &(dyn ToSql + Sync)).collect();
+        postgres::Transaction::execute(self, query, &params)?;
+        Ok(())
+    }
+
+    fn query(&mut self, query: &str, params: &[&dyn ToValue]) -> Result<Vec<Vec<Value>>, Error> {
+        let values: Vec<_> = params.iter().map(|p| p.to_value()).collect();
+        let params: Vec<_> = values.iter().map(|v| v as &(dyn ToSql + Sync)).collect();
+        let rows = postgres::Transaction::query(self, query, &params)?;
+        rows.iter()
+            .map(|row| (0..row.len()).map(|i| postgres_value(row, i)).collect())
+            .collect()
+    }
+
+    fn create_enum(&mut self, name: &str, variants: &[&str]) -> Result<(), Error> {
+        let variants: Vec<_> = variants.iter().map(|v| format!("'{v}'")).collect();
+        let query = format!(
+            "DO $$ BEGIN
+                CREATE TYPE {:?} as ENUM ({});
+            EXCEPTION WHEN duplicate_object THEN NULL;
+            END $$;",
+            name,
+            variants.join(","),
+        );
+        Database::batch_execute(self, &query)
+    }
+
+    fn extend_enum(&mut self, name: &str, variants: &[&str]) -> Result<(), Error> {
+        let query: String = variants
+            .iter()
+            .map(|v| format!("ALTER TYPE {name:?} ADD VALUE IF NOT EXISTS '{v}';"))
+            .collect();
+        Database::batch_execute(self, &query)
+    }
+}
+
+impl Database for rusqlite::Transaction<'_> {
+    fn batch_execute(&mut self, query: &str) -> Result<(), Error> {
+        rusqlite::Connection::execute_batch(self, query)?;
+        Ok(())
+    }
+
+    fn execute(&mut self, query: &str, params: &[&dyn ToValue]) -> Result<(), Error> {
+        let values: Vec<_> = params.iter().map(|p| p.to_value()).collect();
+        self.prepare_cached(query)?
+            .execute(rusqlite::params_from_iter(values))?;
+        Ok(())
+    }
+
+    fn query(&mut self, query: &str, params: &[&dyn ToValue]) -> Result<Vec<Vec<Value>>, Error> {
+        let values: Vec<_> = params.iter().map(|p| p.to_value()).collect();
+        let mut stmt = self.prepare_cached(query)?;
+        let len = stmt.column_count();
+        let mut rows = stmt.query(rusqlite::params_from_iter(values))?;
+        let mut out = Vec::new();
+        while let Some(row) = rows.next()? {
+            out.push(
+                (0..len)
+                    .map(|i| sqlite_value(row, i))
+                    .collect::<Result<_, _>>()?,
+            );
+        }
+        Ok(out)
+    }
+
+    fn create_enum(&mut self, _name: &str, _variants: &[&str]) -> Result<(), Error> {
+        // SQLite has no enum types, the labels are stored as TEXT
+        Ok(())
+    }
+
+    fn extend_enum(&mut self, _name: &str, _variants: &[&str]) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A step in the schema of one component, see [`migrate`].
+pub type Migration = fn(&mut dyn Database) -> Result<(), Error>;
+
+crate::sql_row! {
+    #[table = "schema_migrations"]
+    /// A migration step which was applied to the database.
+    pub struct SchemaMigration {
+        pub component: String,
+        pub version: usize,
+    }
+}
+
+/// Apply the migrations of `component` which are not yet recorded in the
+/// `schema_migrations` table. Existing databases are refused if they were
+/// written by a newer crate-stats that knows more migrations than we do.
+pub fn migrate(
+    tx: &mut dyn Database,
+    component: &str,
+    migrations: &[Migration],
+) -> Result<(), Error> {
+    tx.batch_execute(
+        r"CREATE TABLE IF NOT EXISTS schema_migrations (
+            component TEXT,
+            version INT,
+            PRIMARY KEY (component, version)
+        );",
+    )?;
+
+    let mut applied = 0;
+    for row in tx.query(
+        "SELECT version FROM schema_migrations WHERE component = $1",
+        &[&component],
+    )? {
+        let [Value::Int(version)] = row.as_slice() else {
+            bail!("malformed schema_migrations row for {component}: {row:?}");
+        };
+        applied = applied.max(usize::try_from(*version)?);
+    }
+    if applied > migrations.len() {
+        bail!(
+            "the {component} schema is at version {applied}, but this crate-stats only knows \
+            version {}; refusing to write to an incompatible database",
+            migrations.len()
+        );
+    }
+
+    for (i, migration) in migrations.iter().enumerate().skip(applied) {
+        let version = i + 1;
+        migration(tx).with_context(|| format!("migrating {component} to version {version}"))?;
+        let applied = SchemaMigration {
+            component: component.to_string(),
+            version,
+        };
+        tx.insert(SchemaMigration::TABLE, &applied.columns())?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub enum Backend {
+    Postgres(postgres::Config),
+    Sqlite(PathBuf),
+    Parquet(Arc<ParquetDir>),
+}
+
+impl Backend {
+    pub fn connect(&self) -> Result<Connection, Error> {
+        Ok(match self {
+            Backend::Postgres(config) => Connection::Postgres(config.connect(NoTls)?),
+            Backend::Sqlite(path) => {
+                let cli = rusqlite::Connection::open(path)?;
+                // Every rayon worker writes through its own connection
+                cli.busy_timeout(Duration::from_secs(60 * 60))?;
+                cli.pragma_update(None, "journal_mode", "WAL")?;
+                cli.pragma_update(None, "foreign_keys", "ON")?;
+                Connection::Sqlite(cli)
+            }
+            Backend::Parquet(dir) => Connection::Parquet(dir.clone()),
+        })
+    }
+}
+
+pub enum Connection {
+    Postgres(postgres::Client),
+    Sqlite(rusqlite::Connection),
+    Parquet(Arc<ParquetDir>),
+}
+
+impl Connection {
+    pub fn transaction(&mut self) -> Result<Transaction<'_>, Error> {
+        Ok(match self {
+            Connection::Postgres(cli) => {
+                Transaction::Postgres(CopyTransaction::new(cli.transaction()?))
+            }
+            Connection::Sqlite(cli) => {
+                Transaction::Sqlite(cli.transaction_with_behavior(TransactionBehavior::Immediate)?)
+            }
+            Connection::Parquet(dir) => Transaction::Parquet(ParquetTransaction::new(dir)),
+        })
+    }
+}
+
+pub enum Transaction<'c> {
+    Postgres(CopyTransaction<'c>),
+    Sqlite(rusqlite::Transaction<'c>),
+    Parquet(ParquetTransaction<'c>),
+}
+
+impl Transaction<'_> {
+    pub fn commit(self) -> Result<(), Error> {
+        match self {
+            Transaction::Postgres(tx) => tx.commit()?,
+            Transaction::Sqlite(tx) => tx.commit()?,
+            Transaction::Parquet(tx) => tx.commit()?,
+        }
+        Ok(())
+    }
+
+    #[allow(unused)]
+    pub fn rollback(self) -> Result<(), Error> {
+        match self {
+            Transaction::Postgres(tx) => tx.rollback()?,
+            Transaction::Sqlite(tx) => tx.rollback()?,
+            Transaction::Parquet(_) => {}
+        }
+        Ok(())
+    }
+
+    fn inner(&mut self) -> &mut dyn Database {
+        match self {
+            Transaction::Postgres(tx) => tx,
+            Transaction::Sqlite(tx) => tx,
+            Transaction::Parquet(tx) => tx,
+        }
+    }
+}
+
+impl Database for Transaction<'_> {
+    fn batch_execute(&mut self, query: &str) -> Result<(), Error> {
+        self.inner().batch_execute(query)
+    }
+
+    fn execute(&mut self, query: &str, params: &[&dyn ToValue]) -> Result<(), Error> {
+        self.inner().execute(query, params)
+    }
+
+    fn query(&mut self, query: &str, params: &[&dyn ToValue]) -> Result<Vec<Vec<Value>>, Error> {
+        self.inner().query(query, params)
+    }
+
+    fn create_enum(&mut self, name: &str, variants: &[&str]) -> Result<(), Error> {
+        self.inner().create_enum(name, variants)
+    }
+
+    fn extend_enum(&mut self, name: &str, variants: &[&str]) -> Result<(), Error> {
+        self.inner().extend_enum(name, variants)
+    }
+
+    fn insert(&mut self, table: &str, row: &[(&str, &dyn ToValue)]) -> Result<(), Error> {
+        self.inner().insert(table, row)
+    }
+}
+
+/// Keeps inserted rows in memory so tests can run without a database server.
+#[cfg(test)]
+#[derive(Default, Debug)]
+pub struct MemoryDatabase {
+    pub tables: std::collections::HashMap<String, Vec<Vec<(String, Value)>>>,
+}
+
+#[cfg(test)]
+impl MemoryDatabase {
+    pub fn rows(&self, table: &str) -> &[Vec<(String, Value)>] {
+        self.tables
+            .get(table)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Was exactly this row inserted, ignoring the file and version?
+    pub fn contains_row<R: SqlRow>(&self, row: &R) -> bool {
+        self.contains(R::TABLE, &row.columns())
+    }
+
+    /// Is there a row in `table` with all of the given column values?
+    pub fn contains(&self, table: &str, row: &[(&str, &dyn ToValue)]) -> bool {
+        self.rows(table).iter().any(|r| {
+            row.iter()
+                .all(|(column, value)| r.iter().any(|(c, v)| c == column && *v == value.to_value()))
+        })
+    }
+}
+
+#[cfg(test)]
+impl Database for MemoryDatabase {
+    fn batch_execute(&mut self, _query: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn execute(&mut self, query: &str, _params: &[&dyn ToValue]) -> Result<(), Error> {
+        bail!("raw queries are not supported in memory: {query}")
+    }
+
+    fn query(&mut self, _query: &str, _params: &[&dyn ToValue]) -> Result<Vec<Vec<Value>>, Error> {
+        // Nothing is ever stored in memory, so every migration looks unapplied
+        Ok(Vec::new())
+    }
+
+    fn create_enum(&mut self, _name: &str, _variants: &[&str]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn extend_enum(&mut self, _name: &str, _variants: &[&str]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn insert(&mut self, table: &str, row: &[(&str, &dyn ToValue)]) -> Result<(), Error> {
+        let row = row
+            .iter()
+            .map(|(c, v)| (c.to_string(), v.to_value()))
+            .collect();
+        self.tables.entry(table.to_string()).or_default().push(row);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_sqlite_backend() {
+    let mut cli = Backend::Sqlite(":memory:".into()).connect().unwrap();
+    let mut tx = cli.transaction().unwrap();
+    crate::stats::init(&mut tx, crate::stats::ALL_RUNNERS).unwrap();
+
+    let version_id = Uuid::new_v4();
+    tx.execute(
+        r"INSERT INTO versions (id, crate_name, date_str) VALUES ($1, $2, $3)",
+        &[&version_id, &"mock", &""],
+    )
+    .unwrap();
+    assert!(crate::stats::traits::RUNNER.collect_path(
+        "./mocks/impl_for.rs",
+        &mut tx,
+        "impl_for.rs",
+        version_id,
+    ));
+
+    let Transaction::Sqlite(tx) = tx else {
+        unreachable!()
+    };
+    let (syntax, trait_name, version): (String, String, String) = tx
+        .query_row(
+            "SELECT syntax, trait_name, version_id FROM traits",
+            [],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+        )
+        .unwrap();
+    assert_eq!(syntax, "ImplFor");
+    assert_eq!(trait_name, "Iterator");
+    assert_eq!(version, version_id.to_string());
+}
+
+#[test]
+fn test_migrate() {
+    let mut cli = Backend::Sqlite(":memory:".into()).connect().unwrap();
+    crate::sql_row! {
+        #[table = "mock"]
+        struct Mock {
+            a: i32,
+            #[since = 2]
+            b: i32,
+        }
+    }
+    const STEPS: &[Migration] = &[
+        |db| db.batch_execute("CREATE TABLE mock (a INT);"),
+        |db| add_columns::<Mock>(db, 2),
+    ];
+
+    let mut tx = cli.transaction().unwrap();
+    migrate(&mut tx, "mock", &STEPS[..1]).unwrap();
+    tx.commit().unwrap();
+
+    // Attaching again only applies the new step
+    let mut tx = cli.transaction().unwrap();
+    migrate(&mut tx, "mock", STEPS).unwrap();
+    migrate(&mut tx, "mock", STEPS).unwrap();
+    tx.insert("mock", &[("a", &1), ("b", &2)]).unwrap();
+    tx.commit().unwrap();
+
+    // Older binaries refuse the newer schema
+    let mut tx = cli.transaction().unwrap();
+    let err = migrate(&mut tx, "mock", &STEPS[..1]).unwrap_err();
+    assert!(err.to_string().contains("at version 2"), "{err}");
+}
@@ -0,0 +1,322 @@
+use super::{Column, Database, Table, ToValue, Value};
+use anyhow::{anyhow, bail, Context, Error};
+use arrow_array::builder::{BooleanBuilder, Int64Builder, StringBuilder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+type Row = Vec<Value>;
+
+/// A directory with one `<table>.parquet` file per table, shared by all
+/// connections. The files are only readable once [`ParquetDir::close`] wrote
+/// their footers.
+pub struct ParquetDir {
+    path: PathBuf,
+    schemas: HashMap<&'static str, TableSchema>,
+    files: Mutex<Files>,
+}
+
+struct TableSchema {
+    columns: Vec<&'static Column>,
+    arrow: SchemaRef,
+}
+
+#[derive(Default)]
+struct Files {
+    writers: HashMap<&'static str, ArrowWriter<File>>,
+    /// Parquet files can't be updated, so `runs` is only written once
+    /// `finished_at` is known
+    runs: Vec<Row>,
+    committed: bool,
+}
+
+impl fmt::Debug for ParquetDir {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ParquetDir")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ParquetDir {
+    /// Create a file for each of `tables` in `path`, which must not contain
+    /// any of them yet.
+    pub fn create(path: &Path, tables: &[Table]) -> Result<Self, Error> {
+        fs::create_dir_all(path)?;
+        let mut schemas = HashMap::new();
+        let mut files = Files::default();
+        for table in tables {
+            let columns = (table.columns)();
+            let fields: Vec<_> = columns
+                .iter()
+                .map(|c| Field::new(c.name, data_type(c), true))
+                .collect();
+            let arrow = Arc::new(Schema::new(fields));
+            let file_path = path.join(format!("{}.parquet", table.name));
+            let file = File::create_new(&file_path)
+                .with_context(|| format!("creating {}", file_path.display()))?;
+            let writer = ArrowWriter::try_new(file, arrow.clone(), None)?;
+            files.writers.insert(table.name, writer);
+            schemas.insert(table.name, TableSchema { columns, arrow });
+        }
+        Ok(ParquetDir {
+            path: path.to_owned(),
+            schemas,
+            files: Mutex::new(files),
+        })
+    }
+
+    /// The files, unless a panic interrupted an earlier write into them.
+    fn files(&self) -> Result<MutexGuard<'_, Files>, Error> {
+        self.files
+            .lock()
+            .map_err(|_| anyhow!("a write into {} panicked", self.path.display()))
+    }
+
+    fn batch(&self, table: &'static str, rows: &[Row]) -> Result<RecordBatch, Error> {
+        let schema = &self.schemas[table];
+        let arrays = schema
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| {
+                build_array(column, rows.iter().map(|row| &row[i]))
+                    .with_context(|| format!("writing {table}.{}", column.name))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(RecordBatch::try_new(schema.arrow.clone(), arrays)?)
+    }
+
+    fn commit(&self, rows: Vec<(&'static str, Row)>) -> Result<(), Error> {
+        let mut by_table: HashMap<_, Vec<_>> = HashMap::new();
+        for (table, row) in rows {
+            by_table.entry(table).or_default().push(row);
+        }
+        let runs = by_table.remove("runs").unwrap_or_default();
+        // Every batch is built first, so that rows which don't fit their
+        // schema leave all of the files alone
+        let batches = by_table
+            .into_iter()
+            .map(|(table, rows)| Ok((table, self.batch(table, &rows)?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        if !runs.is_empty() {
+            self.batch("runs", &runs)?;
+        }
+
+        let mut files = self.files()?;
+        for (table, batch) in batches {
+            files.writers.get_mut(table).unwrap().write(&batch)?;
+        }
+        files.runs.extend(runs);
+        files.committed = true;
+        Ok(())
+    }
+
+    /// Write the `runs` rows with `finished_at` and finish every file.
+    pub fn close(&self, finished_at: &str) -> Result<(), Error> {
+        let mut files = self.files()?;
+        let mut runs = mem::take(&mut files.runs);
+        if let Some(i) = self
+            .schemas
+            .get("runs")
+            .and_then(|schema| schema.columns.iter().position(|c| c.name == "finished_at"))
+        {
+            for row in &mut runs {
+                row[i] = Value::Text(finished_at.to_string());
+            }
+            let batch = self.batch("runs", &runs)?;
+            files.writers.get_mut("runs").unwrap().write(&batch)?;
+        }
+        for (_, writer) in files.writers.drain() {
+            writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+fn data_type(column: &Column) -> DataType {
+    match column.sql_type {
+        "BOOLEAN" => DataType::Boolean,
+        "INT" => DataType::Int64,
+        // Text, UUIDs and the labels of enums
+        _ => DataType::Utf8,
+    }
+}
+
+fn build_array<'v>(
+    column: &Column,
+    values: impl Iterator<Item = &'v Value>,
+) -> Result<ArrayRef, Error> {
+    Ok(match data_type(column) {
+        DataType::Boolean => {
+            let mut builder = BooleanBuilder::new();
+            for value in values {
+                match value {
+                    Value::Null => builder.append_null(),
+                    Value::Bool(v) => builder.append_value(*v),
+                    v => bail!("expected a boolean, got {v:?}"),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        DataType::Int64 => {
+            let mut builder = Int64Builder::new();
+            for value in values {
+                match value {
+                    Value::Null => builder.append_null(),
+                    Value::Int(v) => builder.append_value(*v),
+                    v => bail!("expected an integer, got {v:?}"),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        _ => {
+            let mut builder = StringBuilder::new();
+            for value in values {
+                match value {
+                    Value::Null => builder.append_null(),
+                    Value::Text(_) | Value::Uuid(_) | Value::Enum(_) => {
+                        builder.append_value(value.to_string())
+                    }
+                    v => bail!("expected text, got {v:?}"),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+    })
+}
+
+/// Rows are buffered until commit, so an aborted snapshot never reaches the files.
+pub struct ParquetTransaction<'c> {
+    dir: &'c ParquetDir,
+    rows: Vec<(&'static str, Row)>,
+}
+
+impl<'c> ParquetTransaction<'c> {
+    pub fn new(dir: &'c ParquetDir) -> Self {
+        ParquetTransaction {
+            dir,
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn commit(self) -> Result<(), Error> {
+        self.dir.commit(self.rows)
+    }
+}
+
+impl Database for ParquetTransaction<'_> {
+    fn batch_execute(&mut self, _query: &str) -> Result<(), Error> {
+        // The files were created with every column up front
+        Ok(())
+    }
+
+    fn execute(&mut self, query: &str, _params: &[&dyn ToValue]) -> Result<(), Error> {
+        bail!("raw queries are not supported by Parquet output: {query}")
+    }
+
+    fn query(&mut self, query: &str, _params: &[&dyn ToValue]) -> Result<Vec<Vec<Value>>, Error> {
+        // `migrate` looks for applied migrations before anything was written,
+        // which a fresh directory has none of; later queries would need to
+        // read the files back, which is why `--resume` is refused
+        if self.dir.files()?.committed {
+            bail!("queries are not supported by Parquet output: {query}");
+        }
+        Ok(Vec::new())
+    }
+
+    fn create_enum(&mut self, _name: &str, _variants: &[&str]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn extend_enum(&mut self, _name: &str, _variants: &[&str]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn insert(&mut self, table: &str, row: &[(&str, &dyn ToValue)]) -> Result<(), Error> {
+        let Some((&table, schema)) = self.dir.schemas.get_key_value(table) else {
+            bail!("no Parquet schema for table {table}");
+        };
+        let mut values = vec![Value::Null; schema.columns.len()];
+        for (column, value) in row {
+            let Some(i) = schema.columns.iter().position(|c| c.name == *column) else {
+                bail!("no column {column} in the Parquet schema of {table}");
+            };
+            values[i] = value.to_value();
+        }
+        self.rows.push((table, values));
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parquet_dir() {
+    use crate::stats::Run;
+    use arrow_array::{Array, StringArray};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    crate::sql_row! {
+        #[table = "mock"]
+        struct Mock {
+            line_number: usize,
+            name: Option<String>,
+        }
+    }
+    let path = std::env::temp_dir().join(format!("crate-stats-{}", uuid::Uuid::new_v4()));
+    let dir = ParquetDir::create(&path, &[Table::of::<Mock>(), Table::of::<Run>()]).unwrap();
+
+    let mut tx = ParquetTransaction::new(&dir);
+    tx.insert("mock", &[("line_number", &1), ("name", &"")])
+        .unwrap();
+    tx.insert("mock", &[("name", &None::<String>), ("line_number", &2)])
+        .unwrap();
+    tx.insert("runs", &[("id", &uuid::Uuid::nil())]).unwrap();
+    assert!(tx.insert("mock", &[("line_count", &3)]).is_err());
+    tx.commit().unwrap();
+
+    // Rolled back
+    let mut tx = ParquetTransaction::new(&dir);
+    tx.insert("mock", &[("line_number", &3)]).unwrap();
+    drop(tx);
+
+    // A row which doesn't fit its column keeps the whole commit out
+    let mut tx = ParquetTransaction::new(&dir);
+    tx.insert("runs", &[("id", &uuid::Uuid::nil())]).unwrap();
+    tx.insert("mock", &[("line_number", &"4")]).unwrap();
+    assert!(tx.commit().is_err());
+
+    let mut tx = ParquetTransaction::new(&dir);
+    assert!(tx.query("SELECT 1", &[]).is_err());
+    dir.close("2022-10-01T00:00:00Z").unwrap();
+
+    let read = |table: &str| {
+        let file = File::open(path.join(format!("{table}.parquet"))).unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(file)
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut batches: Vec<_> = reader.map(Result::unwrap).collect();
+        assert_eq!(batches.len(), 1);
+        batches.remove(0)
+    };
+    let mock = read("mock");
+    assert_eq!(mock.num_rows(), 2);
+    let names = mock.column_by_name("name").unwrap();
+    let names = names.as_any().downcast_ref::<StringArray>().unwrap();
+    // Empty strings are not confused with NULL
+    assert!(names.is_valid(0) && names.value(0).is_empty());
+    assert!(names.is_null(1));
+
+    let runs = read("runs");
+    assert_eq!(runs.num_rows(), 1);
+    let finished_at = runs.column_by_name("finished_at").unwrap();
+    let finished_at = finished_at.as_any().downcast_ref::<StringArray>().unwrap();
+    assert_eq!(finished_at.value(0), "2022-10-01T00:00:00Z");
+    fs::remove_dir_all(path).unwrap();
+}
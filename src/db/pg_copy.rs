@@ -0,0 +1,124 @@
+use super::{Database, ToValue, Value};
+use anyhow::Error;
+use postgres::binary_copy::BinaryCopyInWriter;
+use postgres::types::ToSql;
+
+/// Flush once this many rows are waiting, to bound memory on huge snapshots.
+const MAX_BUFFERED_ROWS: usize = 50_000;
+
+/// A postgres transaction which buffers inserted rows and writes them with
+/// binary `COPY ... FROM STDIN` instead of one round-trip per row.
+pub struct CopyTransaction<'c> {
+    tx: postgres::Transaction<'c>,
+    batches: Vec<Batch>,
+    buffered: usize,
+}
+
+struct Batch {
+    table: String,
+    columns: Vec<String>,
+    rows: Vec<Vec<Value>>,
+}
+
+impl<'c> CopyTransaction<'c> {
+    pub fn new(tx: postgres::Transaction<'c>) -> Self {
+        CopyTransaction {
+            tx,
+            batches: Vec::new(),
+            buffered: 0,
+        }
+    }
+
+    /// Write all buffered rows, in the order their tables were first inserted
+    /// into so that foreign keys (e.g. to `versions`) are satisfied.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        for batch in self.batches.drain(..) {
+            let columns = batch.columns.join(", ");
+            let types: Vec<_> = self
+                .tx
+                .prepare(&format!("SELECT {columns} FROM {} LIMIT 0", batch.table))?
+                .columns()
+                .iter()
+                .map(|c| c.type_().clone())
+                .collect();
+            let copy = self.tx.copy_in(&format!(
+                "COPY {} ({columns}) FROM STDIN BINARY",
+                batch.table
+            ))?;
+            let mut writer = BinaryCopyInWriter::new(copy, &types);
+            for row in &batch.rows {
+                let row: Vec<_> = row.iter().map(|v| v as &(dyn ToSql + Sync)).collect();
+                writer.write(&row)?;
+            }
+            writer.finish()?;
+        }
+        self.buffered = 0;
+        Ok(())
+    }
+
+    pub fn commit(mut self) -> Result<(), Error> {
+        self.flush()?;
+        self.tx.commit()?;
+        Ok(())
+    }
+
+    pub fn rollback(self) -> Result<(), Error> {
+        self.tx.rollback()?;
+        Ok(())
+    }
+}
+
+impl Database for CopyTransaction<'_> {
+    fn batch_execute(&mut self, query: &str) -> Result<(), Error> {
+        self.flush()?;
+        Database::batch_execute(&mut self.tx, query)
+    }
+
+    fn execute(&mut self, query: &str, params: &[&dyn ToValue]) -> Result<(), Error> {
+        self.flush()?;
+        Database::execute(&mut self.tx, query, params)
+    }
+
+    fn query(&mut self, query: &str, params: &[&dyn ToValue]) -> Result<Vec<Vec<Value>>, Error> {
+        self.flush()?;
+        Database::query(&mut self.tx, query, params)
+    }
+
+    fn create_enum(&mut self, name: &str, variants: &[&str]) -> Result<(), Error> {
+        self.flush()?;
+        self.tx.create_enum(name, variants)
+    }
+
+    fn extend_enum(&mut self, name: &str, variants: &[&str]) -> Result<(), Error> {
+        self.flush()?;
+        self.tx.extend_enum(name, variants)
+    }
+
+    fn insert(&mut self, table: &str, row: &[(&str, &dyn ToValue)]) -> Result<(), Error> {
+        let matches = |b: &&mut Batch| {
+            b.table == table
+                && b.columns.len() == row.len()
+                && b.columns.iter().zip(row).all(|(a, (b, _))| a == b)
+        };
+        let batch = match self.batches.iter_mut().find(matches) {
+            Some(batch) => batch,
+            None => {
+                self.batches.push(Batch {
+                    table: table.to_string(),
+                    columns: row.iter().map(|(c, _)| c.to_string()).collect(),
+                    rows: Vec::new(),
+                });
+                self.batches.last_mut().unwrap()
+            }
+        };
+        batch
+            .rows
+            .push(row.iter().map(|(_, v)| v.to_value()).collect());
+
+        self.buffered += 1;
+        if self.buffered >= MAX_BUFFERED_ROWS {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{sql_enum, sql_row};
+use anyhow::{bail, Context, Error};
+use glob::{MatchOptions, Pattern};
+use std::fs;
+use std::path::Path;
+
+sql_enum! {
+    pub enum ExclusionReason {
+        /// Matched an `--exclude` glob
+        Excluded,
+        /// Matched none of the `--include` globs
+        NotIncluded,
+        /// Inside a directory with a `.cargo-checksum.json`, i.e. `cargo vendor` output
+        Vendored,
+        /// Marked `@generated` at the top of the file
+        Generated,
+    }
+}
+
+sql_row! {
+    #[table = "excluded_files"]
+    /// A Rust file of a version which was deliberately not analyzed.
+    pub struct ExcludedFile {
+        pub reason: ExclusionReason,
+        pub line_count: usize,
+    }
+}
+
+/// Decides which files of a snapshot are analyzed. Globs are matched against
+/// the path relative to the repository.
+#[derive(Default)]
+pub struct FileFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl FileFilter {
+    /// Combine the globs given on the command line with those of a filters file,
+    /// which has one `include <glob>` or `exclude <glob>` per line.
+    pub fn new(
+        include: &[Pattern],
+        exclude: &[Pattern],
+        filters: Option<&Path>,
+    ) -> Result<Self, Error> {
+        let mut filter = FileFilter {
+            include: include.to_vec(),
+            exclude: exclude.to_vec(),
+        };
+        let Some(path) = filters else {
+            return Ok(filter);
+        };
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let context = || format!("{}:{}", path.display(), i + 1);
+            let (kind, glob) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let pattern = Pattern::new(glob.trim()).with_context(context)?;
+            match kind {
+                "include" => filter.include.push(pattern),
+                "exclude" => filter.exclude.push(pattern),
+                _ => bail!("{}: expected `include` or `exclude`", context()),
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Why `file_name` shouldn't be analyzed, if it shouldn't be.
+    pub fn exclusion(
+        &self,
+        file_name: &str,
+        source: Option<&str>,
+        vendored_dirs: &[String],
+    ) -> Option<ExclusionReason> {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        let matches = |pattern: &Pattern| pattern.matches_with(file_name, options);
+        if self.exclude.iter().any(matches) {
+            Some(ExclusionReason::Excluded)
+        } else if !self.include.is_empty() && !self.include.iter().any(matches) {
+            Some(ExclusionReason::NotIncluded)
+        } else if vendored_dirs
+            .iter()
+            .any(|dir| Path::new(file_name).starts_with(dir))
+        {
+            Some(ExclusionReason::Vendored)
+        } else if source.is_some_and(is_generated) {
+            Some(ExclusionReason::Generated)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether the header of `source` contains the `@generated` marker used by
+/// prost, buck and friends.
+fn is_generated(source: &str) -> bool {
+    source
+        .lines()
+        .take(5)
+        .any(|line| line.contains("@generated"))
+}
+
+#[test]
+fn test_exclusion() {
+    let path = std::env::temp_dir().join(format!("crate-stats-{}", uuid::Uuid::new_v4()));
+    fs::write(&path, "# skip the fuzzers\nexclude fuzz/**\n").unwrap();
+    let filter = FileFilter::new(
+        &[Pattern::new("**/src/**").unwrap()],
+        &[Pattern::new("third_party/**").unwrap()],
+        Some(&path),
+    )
+    .unwrap();
+    fs::remove_file(path).unwrap();
+
+    let vendored = ["crates/dep".to_string()];
+    let exclusion = |file_name, source| filter.exclusion(file_name, Some(source), &vendored);
+    assert_eq!(exclusion("src/lib.rs", "fn f() {}"), None);
+    assert_eq!(exclusion("crates/a/src/lib.rs", "fn f() {}"), None);
+    assert_eq!(
+        exclusion("third_party/x/src/lib.rs", ""),
+        Some(ExclusionReason::Excluded)
+    );
+    assert_eq!(
+        exclusion("fuzz/src/main.rs", ""),
+        Some(ExclusionReason::Excluded)
+    );
+    assert_eq!(
+        exclusion("build.rs", ""),
+        Some(ExclusionReason::NotIncluded)
+    );
+    assert_eq!(
+        exclusion("crates/dep/src/lib.rs", ""),
+        Some(ExclusionReason::Vendored)
+    );
+    assert_eq!(
+        exclusion(
+            "src/proto.rs",
+            "// This file is @generated by prost-build.\n"
+        ),
+        Some(ExclusionReason::Generated)
+    );
+}
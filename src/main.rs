@@ -1,21 +1,44 @@
+use anyhow::Error;
+use cache::{ParseCache, Recorder};
 use chrono::Datelike;
-use git2::Repository;
+use clap::builder::PossibleValuesParser;
+use db::{Backend, Connection, Database, ParquetDir, SqlRow, Value};
+use filter::{ExcludedFile, FileFilter};
+use git2::{Oid, Repository};
 use humantime::format_rfc3339_seconds;
 use ignore::WalkBuilder;
-use postgres::{NoTls, Transaction};
-use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
-use stats::{Logger, ALL_RUNNERS};
+use postgres::NoTls;
+use rayon::prelude::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+use roles::{file_role, TestModules};
+use serde::{Serialize, Serializer};
+use source_map::SourceMap;
+use stats::{
+    FailureKind, LanguageStats, Logger, ParseFailure, Run, RunError, RunPhase, RunRunner, Runner,
+    Version, ALL_RUNNERS,
+};
+use std::any::Any;
+use std::collections::{BTreeMap, HashSet};
 use std::env::var;
 use std::fs;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::SystemTime;
-use tokei::{Config, LanguageType, Languages};
+use tokei::{CodeStats, Config, LanguageType, Languages};
 use uuid::Uuid;
+use workspace::{SourceFile, Workspace};
 
+mod cache;
+mod db;
+mod filter;
+mod names;
+mod roles;
+mod source_map;
 mod stats;
 mod utils;
+mod workspace;
 
-#[derive(clap::Parser, Debug)]
+#[derive(clap::Parser, Debug, Serialize)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// Directory or file to parse
@@ -23,11 +46,51 @@ struct Args {
     source: PathBuf,
     /// Arguments to postgres client
     #[arg(short = 'c', long, default_value = "host=localhost")]
+    // Left out of `runs`, since it may hold a password
+    #[serde(skip)]
     postgres: postgres::Config,
+    /// SQLite file to store results in, instead of using postgres
+    #[arg(long)]
+    sqlite: Option<PathBuf>,
+    /// Directory to write one Parquet file per table into, instead of using
+    /// postgres. It can't be resumed, since the files are write-only.
+    #[arg(long, conflicts_with_all = ["sqlite", "resume"])]
+    parquet: Option<PathBuf>,
 
     #[arg(long, default_value_t = default_postfix())]
     postfix: String,
 
+    /// Comma separated runners to execute, defaults to all of them
+    #[arg(long, value_delimiter = ',', value_parser = PossibleValuesParser::new(ALL_RUNNERS.iter().map(|r| r.name)))]
+    runners: Vec<String>,
+
+    /// Print the available runners and the tables they produce
+    #[arg(long)]
+    list_runners: bool,
+
+    /// Read snapshots from the git object database instead of checking them
+    /// out, analyzing the snapshots of each repository in parallel.
+    #[arg(long)]
+    no_checkout: bool,
+
+    /// Only analyze files matching one of these globs, relative to the repository
+    #[arg(long, value_parser = parse_glob)]
+    #[serde(serialize_with = "serialize_globs")]
+    include: Vec<glob::Pattern>,
+
+    /// Don't analyze files matching any of these globs, relative to the repository
+    #[arg(long, value_parser = parse_glob)]
+    #[serde(serialize_with = "serialize_globs")]
+    exclude: Vec<glob::Pattern>,
+
+    /// File with more globs, one `include <glob>` or `exclude <glob>` per line
+    #[arg(long)]
+    filters: Option<PathBuf>,
+
+    /// Reuse the database and skip snapshots which already have a `versions` row
+    #[arg(long)]
+    resume: bool,
+
     /// Start date in mm-yyyy format.
     #[arg(long, value_parser = MonthYearParser, default_value = "05-2015")]
     start_date: (u32, u32),
@@ -63,9 +126,17 @@ impl clap::builder::TypedValueParser for MonthYearParser {
     }
 }
 
+fn parse_glob(glob: &str) -> Result<glob::Pattern, glob::PatternError> {
+    glob::Pattern::new(glob)
+}
+
+fn serialize_globs<S: Serializer>(globs: &[glob::Pattern], s: S) -> Result<S::Ok, S::Error> {
+    s.collect_seq(globs.iter().map(glob::Pattern::as_str))
+}
+
 fn default_postfix() -> String {
     let now = SystemTime::now();
-    let now_timestamp = format_rfc3339_seconds(now.into());
+    let now_timestamp = format_rfc3339_seconds(now);
 
     let hostname = hostname::get().unwrap();
     let hostname = hostname.to_str().unwrap().split(".").next().unwrap_or("");
@@ -73,7 +144,14 @@ fn default_postfix() -> String {
     format!("{now_timestamp}_{hostname}")
 }
 
-fn run_sources(source_path: &Path, args: &Args) {
+fn run_sources(
+    source_path: &Path,
+    args: &Args,
+    backend: &Backend,
+    runners: &[Runner],
+    run_id: Uuid,
+    filter: &FileFilter,
+) {
     let source_paths: Vec<_> = fs::read_dir(source_path)
         .unwrap()
         .map(|d| d.unwrap())
@@ -81,7 +159,107 @@ fn run_sources(source_path: &Path, args: &Args) {
         .map(|d| d.path())
         .collect();
 
-    source_paths.par_iter().for_each(|d| run_versions(d, args))
+    source_paths.par_iter().for_each(|d| {
+        let crate_name = d
+            .components()
+            .next_back()
+            .unwrap()
+            .as_os_str()
+            .to_string_lossy();
+        isolate(backend, run_id, &crate_name, None, RunPhase::Open, |_| {
+            run_versions(d, &crate_name, args, backend, runners, run_id, filter)
+        });
+    })
+}
+
+/// Record who ran what with which settings in `runs`, returning the id which
+/// the `versions` of this run refer to.
+fn start_run(backend: &Backend, args: &Args, runners: &[Runner]) -> Result<Uuid, Error> {
+    let run_id = Uuid::new_v4();
+    let hostname = hostname::get()?.to_string_lossy().into_owned();
+    let started_at = format_rfc3339_seconds(SystemTime::now()).to_string();
+
+    let mut cli = backend.connect()?;
+    let mut tx = cli.transaction()?;
+    let run = Run {
+        id: run_id,
+        git_hash: env!("CRATE_STATS_GIT_HASH").to_string(),
+        args: serde_json::to_string(args)?,
+        hostname,
+        started_at,
+        finished_at: None,
+    };
+    tx.insert(Run::TABLE, &run.columns())?;
+    for run in runners {
+        let runner = RunRunner {
+            run_id,
+            runner: run.name.to_string(),
+            schema_version: run.migrations.len(),
+            logic_version: run.version,
+        };
+        tx.insert(RunRunner::TABLE, &runner.columns())?;
+    }
+    tx.commit()?;
+    Ok(run_id)
+}
+
+fn finish_run(backend: &Backend, run_id: Uuid) -> Result<(), Error> {
+    let finished_at = format_rfc3339_seconds(SystemTime::now()).to_string();
+    if let Backend::Parquet(dir) = backend {
+        return dir.close(&finished_at);
+    }
+    let mut cli = backend.connect()?;
+    let mut tx = cli.transaction()?;
+    tx.execute(
+        "UPDATE runs SET finished_at = $1 WHERE id = $2",
+        &[&finished_at, &run_id],
+    )?;
+    tx.commit()
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Run `step`, which updates `phase` as it goes. If it panics, the transaction
+/// it had open is rolled back by unwinding and the panic is recorded in
+/// `run_errors`, so that the run can go on with the next crate or snapshot.
+fn isolate(
+    backend: &Backend,
+    run_id: Uuid,
+    crate_name: &str,
+    date_str: Option<&str>,
+    mut phase: RunPhase,
+    step: impl FnOnce(&mut RunPhase),
+) {
+    let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| step(&mut phase))) else {
+        return;
+    };
+    let error = RunError {
+        crate_name: crate_name.to_string(),
+        phase,
+        date_str: date_str.map(str::to_owned),
+        file_name: None,
+        message: panic_message(&*payload),
+        run_id,
+    };
+    eprintln!("Error in {crate_name} during {phase:?}: {}", error.message);
+    if let Err(err) = record_run_error(backend, &error) {
+        eprintln!("Error recording {error:?}: {err:#}");
+    }
+}
+
+fn record_run_error(backend: &Backend, error: &RunError) -> Result<(), Error> {
+    let mut cli = backend.connect()?;
+    let mut tx = cli.transaction()?;
+    tx.insert(RunError::TABLE, &error.columns())?;
+    tx.commit()
 }
 
 fn find_rust_files(path: &Path) -> impl Iterator<Item = PathBuf> {
@@ -93,19 +271,251 @@ fn find_rust_files(path: &Path) -> impl Iterator<Item = PathBuf> {
         .types(matcher)
         .build()
         .map(|f| f.unwrap())
-        .filter(|d| d.file_type().unwrap().is_file())
+        // Files matching the type are walked even if they're hidden, unlike
+        // in tokei's counts and in `tree_snapshot`
+        .filter(|d| {
+            d.file_type().unwrap().is_file() && !d.file_name().to_string_lossy().starts_with('.')
+        })
         .map(|d| d.into_path())
 }
 
-fn run_versions(source_path: &Path, args: &Args) {
-    let crate_name = source_path
-        .components()
-        .last()
-        .unwrap()
-        .as_os_str()
-        .to_string_lossy();
+fn find_manifests(path: &Path) -> impl Iterator<Item = PathBuf> {
+    WalkBuilder::new(path)
+        .build()
+        .map(|f| f.unwrap())
+        .filter(|d| d.file_name() == "Cargo.toml" && d.file_type().unwrap().is_file())
+        .map(|d| d.into_path())
+}
+
+/// Directories below `path` which were created by `cargo vendor`.
+fn find_vendored_dirs(path: &Path) -> Vec<String> {
+    WalkBuilder::new(path)
+        .hidden(false)
+        .filter_entry(|d| d.file_name() != ".git")
+        .build()
+        .map(|f| f.unwrap())
+        .filter(|d| d.file_name() == ".cargo-checksum.json")
+        .filter_map(|d| {
+            let dir = d.path().parent()?.strip_prefix(path).ok()?;
+            (dir != Path::new("")).then(|| dir.display().to_string())
+        })
+        .collect()
+}
+
+/// Dates of the snapshots of `crate_name` which were committed by an earlier run.
+fn analyzed_dates(cli: &mut Connection, crate_name: &str) -> HashSet<String> {
+    let mut tx = cli.transaction().unwrap();
+    let rows = tx
+        .query(
+            "SELECT date_str FROM versions WHERE crate_name = $1",
+            &[&crate_name],
+        )
+        .unwrap();
+    tx.commit().unwrap();
+    rows.into_iter()
+        .filter_map(|row| match row.into_iter().next() {
+            Some(Value::Text(date_str)) => Some(date_str),
+            _ => None,
+        })
+        .collect()
+}
 
-    let mut cli = args.postgres.connect(NoTls).unwrap();
+/// The Rust sources of one snapshot of a crate.
+struct Snapshot {
+    commit_id: Oid,
+    /// Commit time as seconds since the epoch
+    commit_time: i64,
+    /// Relative path and contents of each Rust file which is analyzed
+    files: Vec<(String, Result<String, String>)>,
+    /// Relative path of each Rust file which is not
+    excluded: Vec<(String, ExcludedFile)>,
+    /// Relative path and contents of each `Cargo.toml`, except vendored ones
+    manifests: Vec<(String, String)>,
+    /// Totals of every language, including files which are not analyzed, for
+    /// a version that isn't known yet (their `version_id` is nil)
+    languages: Vec<LanguageStats>,
+    line_count_rust: usize,
+}
+
+impl Snapshot {
+    /// Set aside the files which `filter` excludes.
+    fn new(
+        commit: &git2::Commit,
+        files: Vec<(String, Result<String, String>)>,
+        mut manifests: Vec<(String, String)>,
+        vendored_dirs: &[String],
+        filter: &FileFilter,
+        languages: Vec<LanguageStats>,
+    ) -> Self {
+        let config = Config::default();
+        let line_count_rust = languages
+            .iter()
+            .find(|l| l.language == LanguageType::Rust.name())
+            .map_or(0, |l| l.code);
+        manifests.retain(|(name, _)| {
+            !vendored_dirs
+                .iter()
+                .any(|dir| Path::new(name).starts_with(dir))
+        });
+        let mut snapshot = Snapshot {
+            commit_id: commit.id(),
+            commit_time: commit.time().seconds(),
+            files: Vec::new(),
+            excluded: Vec::new(),
+            manifests,
+            languages,
+            line_count_rust,
+        };
+        for (file_name, source) in files {
+            match filter.exclusion(&file_name, source.as_deref().ok(), vendored_dirs) {
+                Some(reason) => {
+                    let line_count = source.map_or(0, |source| {
+                        LanguageType::Rust.parse_from_str(source, &config).code
+                    });
+                    let row = ExcludedFile { reason, line_count };
+                    snapshot.excluded.push((file_name, row));
+                }
+                None => snapshot.files.push((file_name, source)),
+            }
+        }
+        snapshot
+    }
+}
+
+/// Read the snapshot of `commit`, which is currently checked out at `source_path`.
+fn checkout_snapshot(source_path: &Path, commit: &git2::Commit, filter: &FileFilter) -> Snapshot {
+    let paths = &[source_path];
+    let excluded = &[];
+    let config = Config::default();
+
+    let mut languages = Languages::new();
+    languages.get_statistics(paths, excluded, &config);
+    let languages = languages
+        .iter()
+        .filter(|(_, stats)| !stats.is_empty())
+        .map(|(language, stats)| LanguageStats {
+            language: language.name().to_string(),
+            files: stats.reports.len(),
+            code: stats.code,
+            comments: stats.comments,
+            blanks: stats.blanks,
+            version_id: Uuid::nil(),
+        })
+        .collect();
+
+    let mut files = Vec::new();
+    for path in find_rust_files(source_path) {
+        let path = path.canonicalize().unwrap();
+        let rel_path = path.strip_prefix(source_path).unwrap();
+        let source = fs::read_to_string(&path).map_err(|err| err.to_string());
+        files.push((rel_path.display().to_string(), source));
+    }
+
+    let mut manifests = Vec::new();
+    for path in find_manifests(source_path) {
+        let rel_path = path.strip_prefix(source_path).unwrap();
+        match fs::read_to_string(&path) {
+            Ok(contents) => manifests.push((rel_path.display().to_string(), contents)),
+            Err(err) => eprintln!("Error reading {}: {err}", path.display()),
+        }
+    }
+
+    let vendored_dirs = find_vendored_dirs(source_path);
+    Snapshot::new(commit, files, manifests, &vendored_dirs, filter, languages)
+}
+
+/// Add a file of `language` to the totals of a snapshot.
+fn add_stats(
+    languages: &mut BTreeMap<LanguageType, LanguageStats>,
+    language: LanguageType,
+    stats: &CodeStats,
+) {
+    let total = languages.entry(language).or_insert_with(|| LanguageStats {
+        language: language.name().to_string(),
+        files: 0,
+        code: 0,
+        comments: 0,
+        blanks: 0,
+        version_id: Uuid::nil(),
+    });
+    total.files += 1;
+    total.code += stats.code;
+    total.comments += stats.comments;
+    total.blanks += stats.blanks;
+}
+
+/// Read the snapshot at `commit` straight from the object database.
+fn tree_snapshot(repo: &Repository, commit: &git2::Commit, filter: &FileFilter) -> Snapshot {
+    let config = Config::default();
+    let mut files = Vec::new();
+    let mut manifests = Vec::new();
+    let mut vendored_dirs = Vec::new();
+    let mut languages = BTreeMap::new();
+
+    let tree = commit.tree().unwrap();
+    tree.walk(git2::TreeWalkMode::PreOrder, |root, entry| {
+        let name = entry.name().unwrap_or("");
+        if name == ".cargo-checksum.json" && !root.is_empty() {
+            vendored_dirs.push(root.trim_end_matches('/').to_string());
+        }
+        // Like in a checkout, hidden files and directories are skipped
+        let hidden = root.split('/').chain([name]).any(|c| c.starts_with('.'));
+        if hidden || entry.kind() != Some(git2::ObjectType::Blob) {
+            return git2::TreeWalkResult::Ok;
+        }
+        if name == "Cargo.toml" {
+            let blob = repo.find_blob(entry.id()).unwrap();
+            match std::str::from_utf8(blob.content()) {
+                Ok(contents) => manifests.push((format!("{root}{name}"), contents.to_owned())),
+                Err(err) => eprintln!("Error reading {root}{name}: {err}"),
+            }
+        }
+        if !name.ends_with(".rs") {
+            // tokei would look for the shebang of a file without an extension
+            // on disk, so those are only counted in a checkout
+            if Path::new(name).extension().is_none() {
+                return git2::TreeWalkResult::Ok;
+            }
+            if let Some(language) = LanguageType::from_path(name, &config) {
+                let blob = repo.find_blob(entry.id()).unwrap();
+                let stats = language.parse_from_slice(blob.content(), &config);
+                add_stats(&mut languages, language, &stats);
+            }
+            return git2::TreeWalkResult::Ok;
+        }
+        let blob = repo.find_blob(entry.id()).unwrap();
+        let source = std::str::from_utf8(blob.content())
+            .map(str::to_owned)
+            .map_err(|err| err.to_string());
+        if let Ok(source) = &source {
+            let stats = LanguageType::Rust.parse_from_str(source, &config);
+            add_stats(&mut languages, LanguageType::Rust, &stats);
+        }
+
+        files.push((format!("{root}{name}"), source));
+        git2::TreeWalkResult::Ok
+    })
+    .unwrap();
+
+    let languages = languages.into_values().collect();
+    Snapshot::new(commit, files, manifests, &vendored_dirs, filter, languages)
+}
+
+fn run_versions(
+    source_path: &Path,
+    crate_name: &str,
+    args: &Args,
+    backend: &Backend,
+    runners: &[Runner],
+    run_id: Uuid,
+    filter: &FileFilter,
+) {
+    let mut cli = backend.connect().unwrap();
+    let analyzed = if args.resume {
+        analyzed_dates(&mut cli, crate_name)
+    } else {
+        HashSet::new()
+    };
 
     let Ok(repo) = Repository::open(source_path) else {
         eprintln!("{} is not a git repository!", source_path.display());
@@ -120,12 +530,14 @@ fn run_versions(source_path: &Path, args: &Args) {
     let starting_idx = args.start_date.0 + args.start_date.1 * 12;
     let ending_idx = args.end_date.0 + args.end_date.1 * 12;
     let mut target_idx = ending_idx;
+    let mut cache = ParseCache::default();
+    let mut queued = Vec::new();
 
     'revwalk: for oid in repo_revwalk {
         let oid = oid.unwrap();
         let commit = repo.find_commit(oid).unwrap();
         let seconds_since_epoch = commit.time().seconds();
-        let time = chrono::NaiveDateTime::from_timestamp_opt(seconds_since_epoch, 0).unwrap();
+        let time = chrono::DateTime::from_timestamp(seconds_since_epoch, 0).unwrap();
         let current_idx = time.month0() + time.year_ce().1 * 12;
 
         while current_idx <= target_idx {
@@ -135,110 +547,708 @@ fn run_versions(source_path: &Path, args: &Args) {
 
             let current_date = format!("{}-{}", current_idx % 12 + 1, current_idx / 12);
             let target_date = format!("{}-{}", target_idx % 12 + 1, target_idx / 12);
+            if analyzed.contains(&target_date) {
+                println!("Skipping {crate_name} for {target_date}, already analyzed.");
+                target_idx -= 1;
+                continue;
+            }
+            if args.no_checkout {
+                queued.push((oid, current_date, target_date));
+                target_idx -= 1;
+                continue;
+            }
             println!("Checking out {crate_name} at {current_date} for {target_date}.");
-            repo.checkout_tree(
-                commit.as_object(),
-                Some(git2::build::CheckoutBuilder::new().force()),
-            )
-            .unwrap();
-            let mut tx = cli.transaction().unwrap();
-            run_version(source_path, &mut tx, &crate_name, &target_date);
-            tx.commit().unwrap();
+            isolate(
+                backend,
+                run_id,
+                crate_name,
+                Some(&target_date),
+                RunPhase::Checkout,
+                |phase| {
+                    repo.checkout_tree(
+                        commit.as_object(),
+                        Some(git2::build::CheckoutBuilder::new().force()),
+                    )
+                    .unwrap();
+                    *phase = RunPhase::Read;
+                    let snapshot = checkout_snapshot(source_path, &commit, filter);
+                    *phase = RunPhase::Analyze;
+                    let mut rows = Recorder::default();
+                    run_version(
+                        &snapshot,
+                        &mut rows,
+                        run_id,
+                        crate_name,
+                        &target_date,
+                        runners,
+                        &mut cache,
+                    );
+                    *phase = RunPhase::Commit;
+                    let mut tx = cli.transaction().unwrap();
+                    rows.write(&mut tx).unwrap();
+                    tx.commit().unwrap();
+                },
+            );
+            cache.finish_snapshot();
             target_idx -= 1;
         }
     }
+
+    // Each job of the pool walks a run of neighbouring snapshots, so it keeps
+    // its own repository handle, connection and parse cache
+    queued.into_par_iter().for_each_init(
+        || {
+            let repo = Repository::open(source_path).unwrap();
+            (repo, backend.connect().unwrap(), ParseCache::default())
+        },
+        |(repo, cli, cache), (oid, current_date, target_date)| {
+            println!("Reading {crate_name} at {current_date} for {target_date}.");
+            isolate(
+                backend,
+                run_id,
+                crate_name,
+                Some(&target_date),
+                RunPhase::Read,
+                |phase| {
+                    let commit = repo.find_commit(oid).unwrap();
+                    let snapshot = tree_snapshot(repo, &commit, filter);
+                    *phase = RunPhase::Analyze;
+                    let mut rows = Recorder::default();
+                    run_version(
+                        &snapshot,
+                        &mut rows,
+                        run_id,
+                        crate_name,
+                        &target_date,
+                        runners,
+                        cache,
+                    );
+                    *phase = RunPhase::Commit;
+                    let mut tx = cli.transaction().unwrap();
+                    rows.write(&mut tx).unwrap();
+                    tx.commit().unwrap();
+                },
+            );
+            cache.finish_snapshot();
+        },
+    );
 }
 
-fn run_version(source_path: &Path, tx: &mut Transaction, crate_name: &str, date_str: &str) {
-    let version_id = Uuid::new_v4();
+/// Namespace of the UUIDv5 version ids.
+const VERSION_NAMESPACE: Uuid = Uuid::from_u128(0xecf9359e5867450da1da11e22591db74);
 
-    let paths = &[source_path];
-    let excluded = &[];
+/// The id of the version of `crate_name` for `date_str` at `commit_id`. It only
+/// depends on those, so analyzing a corpus again gives the same ids.
+fn version_id(crate_name: &str, commit_id: Oid, date_str: &str) -> Uuid {
+    let name = format!("{crate_name}\0{commit_id}\0{date_str}");
+    Uuid::new_v5(&VERSION_NAMESPACE, name.as_bytes())
+}
+
+/// Analyze `snapshot` into `db`. Callers collect the rows in a [`Recorder`]
+/// and only then open a transaction, so that concurrent writers aren't held up
+/// while the snapshot is analyzed.
+fn run_version(
+    snapshot: &Snapshot,
+    db: &mut dyn Database,
+    run_id: Uuid,
+    crate_name: &str,
+    date_str: &str,
+    runners: &[Runner],
+    cache: &mut ParseCache,
+) {
+    let version_id = version_id(crate_name, snapshot.commit_id, date_str);
+    let commit_time = chrono::DateTime::from_timestamp(snapshot.commit_time, 0)
+        .unwrap()
+        .format("%Y-%m-%dT%H:%M:%SZ")
+        .to_string();
     let config = Config::default();
+    let line_count = |source: &str| LanguageType::Rust.parse_from_str(source, &config).code;
+    let line_count_included: usize = snapshot
+        .files
+        .iter()
+        .filter_map(|(_, source)| source.as_deref().ok())
+        .map(line_count)
+        .sum();
 
-    let mut languages = Languages::new();
-    languages.get_statistics(paths, excluded, &config);
-    let rust = &languages[&LanguageType::Rust]
+    let version = Version {
+        id: version_id,
+        crate_name: crate_name.to_string(),
+        date_str: date_str.to_string(),
+        line_count_rust: snapshot.line_count_rust,
+        line_count_included,
+        run_id,
+        commit_id: snapshot.commit_id.to_string(),
+        commit_time,
+    };
+    db.insert(Version::TABLE, &version.columns()).unwrap();
 
-    tx.execute(
-        r"INSERT INTO versions (id, crate_name, date_str, line_count_rust) VALUES ($1, $2, $3, $4)",
-        &[&version_id, &crate_name, &date_str, &(rust.code as i32)],
-    )
-    .unwrap();
+    for row in &snapshot.languages {
+        let row = LanguageStats {
+            version_id,
+            ..row.clone()
+        };
+        db.insert(LanguageStats::TABLE, &row.columns()).unwrap();
+    }
 
-    for path in find_rust_files(source_path) {
-        let path = path.canonicalize().unwrap();
-        let rel_path = path.strip_prefix(&source_path).unwrap();
-        if rel_path.components().any(|s| {
-            let s = s.as_os_str().to_str().unwrap();
-            let s = s.trim_end_matches(".rs");
-            s == "test" || s == "tests" || s == "test_data"
-        }) {
-            // Don't include test files
-            continue;
+    for (file_name, row) in &snapshot.excluded {
+        let mut columns = row.columns();
+        columns.push(("file_name", file_name));
+        columns.push(("version_id", &version_id));
+        db.insert(ExcludedFile::TABLE, &columns).unwrap();
+    }
+
+    let workspace = Workspace::new(
+        &snapshot.manifests,
+        snapshot
+            .files
+            .iter()
+            .map(|(file_name, _)| file_name.as_str())
+            .chain(
+                snapshot
+                    .excluded
+                    .iter()
+                    .map(|(file_name, _)| file_name.as_str()),
+            ),
+    );
+    workspace.insert_manifests(db, version_id).unwrap();
+    let test_modules = TestModules::scan(
+        snapshot
+            .files
+            .iter()
+            .filter_map(|(file_name, source)| Some((file_name.as_str(), source.as_deref().ok()?))),
+    );
+    for (file_name, source) in &snapshot.files {
+        let mut file = workspace.locate(file_name);
+        if let Ok(source) = source {
+            let stats = LanguageType::Rust.parse_from_str(source, &config);
+            file.code = Some(stats.code);
+            file.comments = Some(stats.comments);
+            file.blanks = Some(stats.blanks);
         }
-        let file_name = rel_path.display().to_string();
+        let mut columns = file.columns();
+        columns.push(("file_name", file_name));
+        columns.push(("version_id", &version_id));
+        db.insert(SourceFile::TABLE, &columns).unwrap();
 
-        let source = match fs::read_to_string(&path) {
+        let role = file_role(file_name, file.target_kind, &test_modules);
+        let source = match source {
             Ok(source) => source,
             Err(err) => {
-                eprintln!("Error reading {}: {}", path.display(), err);
-                return;
+                eprintln!("Error reading {crate_name}/{file_name}: {err}");
+                let mut log = Logger {
+                    db,
+                    file_name,
+                    version_id,
+                    source_map: &SourceMap::new("", role),
+                };
+                log.push(&ParseFailure {
+                    kind: FailureKind::Read,
+                    line_number: None,
+                    message: err.clone(),
+                    line_count: 0,
+                    role,
+                });
+                continue;
             }
         };
-        let file = match syn::parse_file(&source) {
+        let key = ParseCache::key(file_name, role, source);
+        if cache.replay(&key, db, version_id).unwrap() {
+            continue;
+        }
+
+        let mut recorder = Recorder::default();
+        let file = match syn::parse_file(source) {
             Ok(f) => f,
             Err(err) => {
-                eprintln!(
-                    "Error parsing {}:{}: {}",
-                    path.display(),
-                    err.span().start().line,
-                    err
-                );
-                return;
+                let line_number = err.span().start().line;
+                eprintln!("Error parsing {crate_name}/{file_name}:{line_number}: {err}");
+                let mut log = Logger {
+                    db: &mut recorder,
+                    file_name,
+                    version_id,
+                    source_map: &SourceMap::new(source, role),
+                };
+                log.push(&ParseFailure {
+                    kind: FailureKind::Parse,
+                    line_number: Some(line_number),
+                    message: err.to_string(),
+                    line_count: line_count(source),
+                    role,
+                });
+                recorder.write(db).unwrap();
+                cache.insert(key, recorder.rows);
+                continue;
             }
         };
 
-        for run in ALL_RUNNERS {
-            run.collect_syntax(
-                &file,
-                Logger {
-                    db: tx,
-                    file_name: &file_name,
-                    version_id,
-                },
+        let mut source_map = SourceMap::new(source, role);
+        source_map.add_items(&file);
+        // A panicking runner loses the rows of this file only
+        let collected = panic::catch_unwind(AssertUnwindSafe(|| {
+            for run in runners {
+                run.collect_syntax(
+                    &file,
+                    Logger {
+                        db: &mut recorder,
+                        file_name,
+                        version_id,
+                        source_map: &source_map,
+                    },
+                );
+            }
+        }));
+        if let Err(payload) = collected {
+            let error = RunError {
+                crate_name: crate_name.to_string(),
+                phase: RunPhase::File,
+                date_str: Some(date_str.to_string()),
+                file_name: Some(file_name.clone()),
+                message: panic_message(&*payload),
+                run_id,
+            };
+            eprintln!(
+                "Error analyzing {crate_name}/{file_name}: {}",
+                error.message
             );
+            db.insert(RunError::TABLE, &error.columns()).unwrap();
+            continue;
         }
+        recorder.write(db).unwrap();
+        cache.insert(key, recorder.rows);
     }
 }
 
 fn main() {
     let mut args: Args = clap::Parser::parse();
+    if args.list_runners {
+        for run in ALL_RUNNERS {
+            let tables: Vec<_> = run.tables.iter().map(|table| table.name).collect();
+            println!("{}: {}", run.name, tables.join(", "));
+        }
+        return;
+    }
+    let filter = match FileFilter::new(&args.include, &args.exclude, args.filters.as_deref()) {
+        Ok(filter) => filter,
+        Err(err) => {
+            eprintln!("Error reading filters: {err:#}");
+            std::process::exit(1);
+        }
+    };
+    let runners: Vec<Runner> = ALL_RUNNERS
+        .iter()
+        .filter(|r| args.runners.is_empty() || args.runners.iter().any(|n| n == r.name))
+        .copied()
+        .collect();
     rayon::ThreadPoolBuilder::new()
         .stack_size(16 * 1024 * 1024)
         .build_global()
         .unwrap();
-    if args.postgres.get_user().is_none() {
-        if let Ok(user) = var("USER") {
-            args.postgres.user(&user);
+    let backend = match (&args.sqlite, &args.parquet) {
+        (Some(path), _) => Backend::Sqlite(path.clone()),
+        (_, Some(path)) => match ParquetDir::create(path, &stats::tables(&runners)) {
+            Ok(dir) => Backend::Parquet(Arc::new(dir)),
+            Err(err) => {
+                eprintln!("Error creating Parquet output: {err:#}");
+                std::process::exit(1);
+            }
+        },
+        (None, None) => {
+            if args.postgres.get_user().is_none() {
+                if let Ok(user) = var("USER") {
+                    args.postgres.user(&user);
+                }
+            }
+            if args.postgres.get_dbname().is_none() {
+                let dbname = format!("crate-stats-{}", args.postfix);
+                if !args.resume {
+                    args.postgres.dbname("crate-stats-template");
+                    let mut cli = args.postgres.connect(NoTls).unwrap();
+                    cli.batch_execute(&format!("CREATE DATABASE {:?}", dbname))
+                        .unwrap();
+                }
+                args.postgres.dbname(&dbname);
+            }
+            Backend::Postgres(args.postgres.clone())
         }
+    };
+    let mut cli = backend.connect().unwrap();
+    let mut tx = cli.transaction().unwrap();
+
+    if let Err(err) = stats::init(&mut tx, &runners) {
+        eprintln!("Error initializing database: {err:#}");
+        std::process::exit(1);
     }
-    if args.postgres.get_dbname().is_none() {
-        let dbname = format!("crate-stats-{}", args.postfix);
-        args.postgres.dbname("crate-stats-template");
-        let mut cli = args.postgres.connect(NoTls).unwrap();
-        cli.batch_execute(&format!("CREATE DATABASE {:?}", dbname))
+    tx.commit().unwrap();
+
+    let run_id = start_run(&backend, &args, &runners).unwrap();
+    run_sources(
+        &args.source.canonicalize().unwrap(),
+        &args,
+        &backend,
+        &runners,
+        run_id,
+        &filter,
+    );
+    finish_run(&backend, run_id).unwrap();
+}
+
+#[test]
+fn test_parse_failures() {
+    let mut db = db::MemoryDatabase::default();
+    stats::init(&mut db, ALL_RUNNERS).unwrap();
+    let snapshot = Snapshot {
+        commit_id: Oid::zero(),
+        commit_time: 0,
+        files: vec![
+            (
+                "src/bad.rs".to_string(),
+                Ok("fn main() {\n    let\n}\n".to_string()),
+            ),
+            (
+                "src/binary.rs".to_string(),
+                Err("invalid utf-8".to_string()),
+            ),
+            (
+                "src/good.rs".to_string(),
+                Ok("fn f() { || 1; }\n".to_string()),
+            ),
+        ],
+        excluded: Vec::new(),
+        manifests: Vec::new(),
+        languages: Vec::new(),
+        line_count_rust: 4,
+    };
+    run_version(
+        &snapshot,
+        &mut db,
+        Uuid::nil(),
+        "mock",
+        "1-2022",
+        ALL_RUNNERS,
+        &mut ParseCache::default(),
+    );
+
+    assert!(db.contains(
+        "versions",
+        &[("line_count_rust", &4), ("line_count_included", &4)]
+    ));
+    assert!(db.contains(
+        "parse_failures",
+        &[
+            ("kind", &FailureKind::Parse),
+            ("line_number", &3),
+            ("line_count", &3),
+            ("file_name", &"src/bad.rs"),
+        ]
+    ));
+    assert!(db.contains(
+        "parse_failures",
+        &[
+            ("kind", &FailureKind::Read),
+            ("file_name", &"src/binary.rs")
+        ]
+    ));
+    // Files after a failure are still analyzed
+    assert!(db.contains("closures", &[("file_name", &"src/good.rs")]));
+}
+
+#[test]
+fn test_panicking_runner() {
+    const PANICKING: Runner = Runner {
+        name: "panicking",
+        version: 1,
+        tables: &[],
+        migrations: &[],
+        collect: |_, _| panic!("runner bug"),
+    };
+    let runners = &[stats::closures::RUNNER, PANICKING];
+    let mut db = db::MemoryDatabase::default();
+    stats::init(&mut db, runners).unwrap();
+    let snapshot = Snapshot {
+        commit_id: Oid::zero(),
+        commit_time: 0,
+        files: vec![(
+            "src/lib.rs".to_string(),
+            Ok("fn f() { || 1; }\n".to_string()),
+        )],
+        excluded: Vec::new(),
+        manifests: Vec::new(),
+        languages: Vec::new(),
+        line_count_rust: 1,
+    };
+    run_version(
+        &snapshot,
+        &mut db,
+        Uuid::nil(),
+        "mock",
+        "1-2022",
+        runners,
+        &mut ParseCache::default(),
+    );
+
+    assert!(db.contains_row(&RunError {
+        crate_name: "mock".to_string(),
+        phase: RunPhase::File,
+        date_str: Some("1-2022".to_string()),
+        file_name: Some("src/lib.rs".to_string()),
+        message: "runner bug".to_string(),
+        run_id: Uuid::nil(),
+    }));
+    // The rows of the other runners are rolled back with the file
+    assert!(db.rows("closures").is_empty());
+}
+
+#[test]
+fn test_run_provenance() {
+    let path = std::env::temp_dir().join(format!("crate-stats-{}.db", Uuid::new_v4()));
+    let backend = Backend::Sqlite(path.clone());
+    let args: Args = clap::Parser::parse_from(["crate-stats", "--runners", "closures"]);
+    let mut cli = backend.connect().unwrap();
+    let mut tx = cli.transaction().unwrap();
+    stats::init(&mut tx, ALL_RUNNERS).unwrap();
+    tx.commit().unwrap();
+
+    let run_id = start_run(&backend, &args, &[stats::closures::RUNNER]).unwrap();
+    finish_run(&backend, run_id).unwrap();
+
+    let mut tx = cli.transaction().unwrap();
+    let rows = tx
+        .query(
+            "SELECT git_hash, finished_at, runner, schema_version, logic_version, args
+            FROM runs JOIN run_runners ON run_id = id",
+            &[],
+        )
+        .unwrap();
+    tx.commit().unwrap();
+    let [row] = rows.as_slice() else {
+        panic!("expected one runner, got {rows:?}")
+    };
+    assert_eq!(
+        row[0],
+        Value::Text(env!("CRATE_STATS_GIT_HASH").to_string())
+    );
+    assert!(matches!(row[1], Value::Text(_)));
+    assert_eq!(row[2], Value::Text("closures".to_string()));
+    assert_eq!(row[3], Value::Int(3));
+    assert_eq!(row[4], Value::Int(1));
+    let Value::Text(args) = &row[5] else {
+        panic!("expected the arguments, got {:?}", row[5])
+    };
+    let args: serde_json::Value = serde_json::from_str(args).unwrap();
+    assert_eq!(args["runners"], serde_json::json!(["closures"]));
+    assert_eq!(args["resume"], serde_json::json!(false));
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_version_id() {
+    let commit_id = Oid::from_str("9febc91a3e5c3d4a1e0ef0b6ae7bc7a4d6bd7a14").unwrap();
+    let id = version_id("foo", commit_id, "10-2022");
+    assert_eq!(id, version_id("foo", commit_id, "10-2022"));
+    assert_eq!(id.get_version_num(), 5);
+    assert_ne!(id, version_id("foo", commit_id, "11-2022"));
+    assert_ne!(id, version_id("bar", commit_id, "10-2022"));
+    assert_ne!(id, version_id("foo", Oid::zero(), "10-2022"));
+}
+
+/// A repository in a temporary directory whose `main` branch has a commit at
+/// each of the given seconds since the epoch, with the files written before it.
+#[cfg(test)]
+fn test_repo(commits: &[(i64, &[(&str, &str)])]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("crate-stats-{}", Uuid::new_v4()));
+    let repo = Repository::init(&path).unwrap();
+    let mut parent = None;
+    for (time, files) in commits {
+        for (file_name, contents) in *files {
+            let file_path = path.join(file_name);
+            fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+            fs::write(file_path, contents).unwrap();
+        }
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"], git2::IndexAddOption::DEFAULT, None)
             .unwrap();
-        args.postgres.dbname(&dbname);
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::new("a", "a@b", &git2::Time::new(*time, 0)).unwrap();
+        let parents: Vec<_> = parent.iter().collect();
+        let oid = repo
+            .commit(
+                Some("refs/heads/main"),
+                &signature,
+                &signature,
+                "snapshot",
+                &tree,
+                &parents,
+            )
+            .unwrap();
+        parent = Some(repo.find_commit(oid).unwrap());
     }
-    let mut cli = args.postgres.connect(NoTls).unwrap();
+    path
+}
+
+#[test]
+fn test_resume() {
+    const JAN_2022: i64 = 1642204800;
+    const FEB_2022: i64 = 1644883200;
+    let source = test_repo(&[
+        (JAN_2022, &[("src/lib.rs", "fn f() { || 1; }\n")]),
+        (FEB_2022, &[("src/lib.rs", "fn f() { || 2; }\n")]),
+    ]);
+    let path = std::env::temp_dir().join(format!("crate-stats-{}.db", Uuid::new_v4()));
+    let backend = Backend::Sqlite(path.clone());
+    let runners = [stats::closures::RUNNER];
+    let filter = FileFilter::new(&[], &[], None).unwrap();
+    let mut cli = backend.connect().unwrap();
     let mut tx = cli.transaction().unwrap();
+    stats::init(&mut tx, &runners).unwrap();
+    tx.commit().unwrap();
 
-    stats::global_init(&mut tx);
+    let args: Args = clap::Parser::parse_from([
+        "crate-stats",
+        "--start-date",
+        "01-2022",
+        "--end-date",
+        "01-2022",
+    ]);
+    let run_id = start_run(&backend, &args, &runners).unwrap();
+    run_versions(&source, "mock", &args, &backend, &runners, run_id, &filter);
 
-    for run in ALL_RUNNERS {
-        (run.init)(&mut tx);
-    }
+    // A snapshot which was interrupted before its commit
+    let mut tx = cli.transaction().unwrap();
+    let version = Version {
+        id: Uuid::new_v4(),
+        crate_name: "mock".to_string(),
+        date_str: "2-2022".to_string(),
+        line_count_rust: 1,
+        line_count_included: 1,
+        run_id,
+        commit_id: Oid::zero().to_string(),
+        commit_time: String::new(),
+    };
+    tx.insert(Version::TABLE, &version.columns()).unwrap();
+    drop(tx);
+
+    let args: Args = clap::Parser::parse_from([
+        "crate-stats",
+        "--resume",
+        "--start-date",
+        "01-2022",
+        "--end-date",
+        "02-2022",
+    ]);
+    let run_id = start_run(&backend, &args, &runners).unwrap();
+    run_versions(&source, "mock", &args, &backend, &runners, run_id, &filter);
+
+    let mut tx = cli.transaction().unwrap();
+    let rows = tx
+        .query(
+            "SELECT date_str, run_id FROM versions ORDER BY date_str",
+            &[],
+        )
+        .unwrap();
+    let errors = tx.query("SELECT message FROM run_errors", &[]).unwrap();
     tx.commit().unwrap();
-    run_sources(&args.source.canonicalize().unwrap(), &args);
+    assert_eq!(errors, Vec::<Vec<Value>>::new());
+    // January is kept from the first run, February is analyzed again
+    let dates: Vec<_> = rows.iter().map(|row| row[0].clone()).collect();
+    assert_eq!(
+        dates,
+        [
+            Value::Text("1-2022".to_string()),
+            Value::Text("2-2022".to_string())
+        ]
+    );
+    assert_eq!(rows[1][1], Value::Text(run_id.to_string()));
+    fs::remove_file(path).unwrap();
+    fs::remove_dir_all(source).unwrap();
+}
+
+#[test]
+fn test_no_checkout() {
+    const JAN_2022: i64 = 1642204800;
+    const FEB_2022: i64 = 1644883200;
+    let source = test_repo(&[
+        (
+            JAN_2022,
+            &[
+                ("Cargo.toml", "[package]\nname = \"mock\"\n"),
+                ("README.md", "# Mock\n\nText\n"),
+                ("src/lib.rs", "mod a;\nfn f() { || 1; }\n"),
+                ("src/a.rs", "fn g() { || 2; }\n"),
+                ("src/.hidden.rs", "fn h() { || 3; }\n"),
+                (".hidden/Cargo.toml", "[package]\nname = \"hidden\"\n"),
+                (".hidden/src/lib.rs", "fn i() { || 4; }\n"),
+                ("vendor/dep/.cargo-checksum.json", "{}"),
+                ("vendor/dep/Cargo.toml", "[package]\nname = \"dep\"\n"),
+                ("vendor/dep/src/lib.rs", "fn j() { || 5; }\n"),
+            ],
+        ),
+        (FEB_2022, &[("src/a.rs", "fn g() { || 2; || 6; }\n")]),
+    ]);
+    let filter = FileFilter::new(&[], &[], None).unwrap();
+
+    let repo = Repository::open(&source).unwrap();
+    let head = repo.revparse_single("heads/main").unwrap();
+    let commit = head.peel_to_commit().unwrap();
+    let from_tree = tree_snapshot(&repo, &commit, &filter);
+    let checked_out = checkout_snapshot(&source.canonicalize().unwrap(), &commit, &filter);
+    fn sorted<T>(mut v: Vec<(String, T)>) -> Vec<(String, T)> {
+        v.sort_by(|a, b| a.0.cmp(&b.0));
+        v
+    }
+    assert_eq!(sorted(from_tree.files.clone()), sorted(checked_out.files));
+    assert_eq!(
+        sorted(from_tree.excluded.clone()),
+        sorted(checked_out.excluded)
+    );
+    assert_eq!(
+        sorted(from_tree.manifests.clone()),
+        sorted(checked_out.manifests)
+    );
+    assert_eq!(from_tree.languages, checked_out.languages);
+    assert_eq!(from_tree.line_count_rust, checked_out.line_count_rust);
+    let file_names: Vec<_> = sorted(from_tree.files).into_iter().map(|f| f.0).collect();
+    assert_eq!(file_names, ["src/a.rs", "src/lib.rs"]);
+
+    // Both modes store the same rows
+    let rows = |no_checkout: bool| {
+        let path = std::env::temp_dir().join(format!("crate-stats-{}.db", Uuid::new_v4()));
+        let backend = Backend::Sqlite(path.clone());
+        let runners = [stats::closures::RUNNER];
+        let mut cli = backend.connect().unwrap();
+        let mut tx = cli.transaction().unwrap();
+        stats::init(&mut tx, &runners).unwrap();
+        tx.commit().unwrap();
+
+        let mut args = vec![
+            "crate-stats",
+            "--start-date",
+            "01-2022",
+            "--end-date",
+            "02-2022",
+        ];
+        if no_checkout {
+            args.push("--no-checkout");
+        }
+        let args: Args = clap::Parser::parse_from(args);
+        let run_id = start_run(&backend, &args, &runners).unwrap();
+        run_versions(&source, "mock", &args, &backend, &runners, run_id, &filter);
+
+        let mut tx = cli.transaction().unwrap();
+        let rows = tx
+            .query(
+                "SELECT v.date_str, v.line_count_included, c.file_name, c.line_number
+                FROM versions v JOIN closures c ON c.version_id = v.id
+                ORDER BY v.date_str, c.file_name, c.start_byte",
+                &[],
+            )
+            .unwrap();
+        tx.commit().unwrap();
+        fs::remove_file(path).unwrap();
+        rows
+    };
+    let checked_out = rows(false);
+    assert_eq!(checked_out.len(), 5);
+    assert_eq!(rows(true), checked_out);
+    fs::remove_dir_all(source).unwrap();
 }
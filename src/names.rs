@@ -0,0 +1,377 @@
+use crate::sql_enum;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+sql_enum! {
+    /// Which crate a resolved path points into.
+    pub enum PathOrigin {
+        /// `std`, `core`, `alloc`, `proc_macro` or `test`
+        Std,
+        /// A dependency
+        External,
+        /// The crate the file belongs to
+        Local,
+    }
+}
+
+const STD_CRATES: &[&str] = &["std", "core", "alloc", "proc_macro", "test"];
+
+/// Traits of the std prelude, which every module imports implicitly.
+const PRELUDE: &[&str] = &[
+    "std::marker::Copy",
+    "std::marker::Send",
+    "std::marker::Sized",
+    "std::marker::Sync",
+    "std::marker::Unpin",
+    "std::ops::Drop",
+    "std::ops::Fn",
+    "std::ops::FnMut",
+    "std::ops::FnOnce",
+    "std::clone::Clone",
+    "std::cmp::Eq",
+    "std::cmp::Ord",
+    "std::cmp::PartialEq",
+    "std::cmp::PartialOrd",
+    "std::convert::AsMut",
+    "std::convert::AsRef",
+    "std::convert::From",
+    "std::convert::Into",
+    "std::convert::TryFrom",
+    "std::convert::TryInto",
+    "std::default::Default",
+    "std::iter::DoubleEndedIterator",
+    "std::iter::ExactSizeIterator",
+    "std::iter::Extend",
+    "std::iter::FromIterator",
+    "std::iter::IntoIterator",
+    "std::iter::Iterator",
+    "std::borrow::ToOwned",
+    "std::string::ToString",
+];
+
+/// Names visible in one module or block.
+#[derive(Default)]
+struct Scope {
+    /// Path of the enclosing module below the crate root
+    module: Vec<String>,
+    /// Paths brought in by `use` and `extern crate ... as`, by name
+    imports: HashMap<String, Vec<String>>,
+    /// Paths of `use ...::*` imports
+    globs: Vec<Vec<String>>,
+    /// Types, traits and modules defined in the scope
+    items: HashSet<String>,
+}
+
+impl Scope {
+    fn add_use(&mut self, tree: &syn::UseTree, prefix: &mut Vec<String>) {
+        match tree {
+            syn::UseTree::Path(p) => {
+                prefix.push(p.ident.to_string());
+                self.add_use(&p.tree, prefix);
+                prefix.pop();
+            }
+            syn::UseTree::Name(n) if n.ident == "self" => {
+                if let Some(last) = prefix.last() {
+                    self.imports.insert(last.clone(), prefix.clone());
+                }
+            }
+            syn::UseTree::Name(n) => {
+                let mut path = prefix.clone();
+                path.push(n.ident.to_string());
+                // Leave `log` to the module in `use log::{self, log};`
+                self.imports.entry(n.ident.to_string()).or_insert(path);
+            }
+            syn::UseTree::Rename(r) if r.rename == "_" => {}
+            syn::UseTree::Rename(r) => {
+                let mut path = prefix.clone();
+                if r.ident != "self" {
+                    path.push(r.ident.to_string());
+                }
+                self.imports.insert(r.rename.to_string(), path);
+            }
+            syn::UseTree::Glob(_) => self.globs.push(prefix.clone()),
+            syn::UseTree::Group(g) => {
+                for tree in &g.items {
+                    self.add_use(tree, prefix);
+                }
+            }
+        }
+    }
+}
+
+/// Best-effort name resolution within one file, from its `use` declarations,
+/// the items it defines and the std prelude. Items from other files are only
+/// found through explicit imports, and macros aren't expanded.
+pub struct Names {
+    /// Innermost last, never empty
+    scopes: Vec<Scope>,
+}
+
+impl Names {
+    pub fn new(file_name: &str, items: &[syn::Item]) -> Self {
+        let mut names = Names { scopes: Vec::new() };
+        names.enter(None, items);
+        names.scopes[0].module = file_module(file_name);
+        names
+    }
+
+    /// Enter the inline module `module`, or a block if it is `None`, which
+    /// contains `items`. Must be paired with [`Names::exit`].
+    pub fn enter<'a>(
+        &mut self,
+        module: Option<&syn::Ident>,
+        items: impl IntoIterator<Item = &'a syn::Item>,
+    ) {
+        let mut scope = Scope {
+            module: self.module().to_vec(),
+            ..Scope::default()
+        };
+        scope.module.extend(module.map(ToString::to_string));
+        for item in items {
+            let ident = match item {
+                syn::Item::Use(item) => {
+                    scope.add_use(&item.tree, &mut Vec::new());
+                    continue;
+                }
+                syn::Item::ExternCrate(syn::ItemExternCrate {
+                    ident,
+                    rename: Some((_, rename)),
+                    ..
+                }) => {
+                    scope
+                        .imports
+                        .insert(rename.to_string(), vec![ident.to_string()]);
+                    continue;
+                }
+                syn::Item::Trait(item) => &item.ident,
+                syn::Item::TraitAlias(item) => &item.ident,
+                syn::Item::Struct(item) => &item.ident,
+                syn::Item::Enum(item) => &item.ident,
+                syn::Item::Union(item) => &item.ident,
+                syn::Item::Type(item) => &item.ident,
+                syn::Item::Mod(item) => &item.ident,
+                _ => continue,
+            };
+            scope.items.insert(ident.to_string());
+        }
+        self.scopes.push(scope);
+    }
+
+    pub fn exit(&mut self) {
+        self.scopes.pop();
+    }
+
+    fn module(&self) -> &[String] {
+        self.scopes.last().map_or(&[], |scope| &scope.module)
+    }
+
+    /// Canonical path of the item `ident` defined in the current scope.
+    pub fn local(&self, ident: &syn::Ident) -> String {
+        let mut path = vec!["crate".to_string()];
+        path.extend_from_slice(self.module());
+        path.push(ident.to_string());
+        path.join("::")
+    }
+
+    /// Canonical path of the item `path` refers to and the crate it is in, or
+    /// `None` if it can't be told from this file.
+    pub fn resolve(&self, path: &syn::Path) -> Option<(String, PathOrigin)> {
+        let segments = path.segments.iter().map(|s| s.ident.to_string()).collect();
+        let canonical = match path.leading_colon {
+            // `::dep::Trait`
+            Some(_) => segments,
+            None => self.canonicalize(self.scopes.len(), segments, &mut Vec::new())?,
+        };
+        let origin = match canonical[0].as_str() {
+            "crate" => PathOrigin::Local,
+            name if STD_CRATES.contains(&name) => PathOrigin::Std,
+            _ => PathOrigin::External,
+        };
+        Some((canonical.join("::"), origin))
+    }
+
+    /// Resolve `path` as seen from the outermost `depth` scopes. `expanding`
+    /// holds the imports being resolved, so that `use log::{self, log};`
+    /// doesn't loop.
+    fn canonicalize(
+        &self,
+        depth: usize,
+        mut path: Vec<String>,
+        expanding: &mut Vec<(usize, String)>,
+    ) -> Option<Vec<String>> {
+        let first = path.first()?.clone();
+        let module = &self.scopes[depth - 1].module;
+        let in_crate = |module: &[String], path: &[String]| {
+            let mut canonical = vec!["crate".to_string()];
+            canonical.extend_from_slice(module);
+            canonical.extend_from_slice(path);
+            canonical
+        };
+        match first.as_str() {
+            "crate" => return Some(path),
+            "self" => return Some(in_crate(module, &path[1..])),
+            "super" => {
+                let supers = path.iter().take_while(|s| *s == "super").count();
+                let parent = module.len().checked_sub(supers)?;
+                return Some(in_crate(&module[..parent], &path[supers..]));
+            }
+            _ => {}
+        }
+
+        for (i, scope) in self.scopes[..depth].iter().enumerate().rev() {
+            let key = (i, first.clone());
+            if let Some(target) = scope.imports.get(&first) {
+                if !expanding.contains(&key) {
+                    expanding.push(key);
+                    let mut canonical = self.canonicalize(i + 1, target.clone(), expanding)?;
+                    expanding.pop();
+                    canonical.extend(path.drain(1..));
+                    return Some(canonical);
+                }
+            }
+            if scope.items.contains(&first) {
+                return Some(in_crate(&scope.module, &path));
+            }
+        }
+
+        // `std::...`, a dependency, or the root of a `use`
+        if path.len() > 1 || !expanding.is_empty() {
+            return Some(path);
+        }
+        if let Some(prelude) = PRELUDE
+            .iter()
+            .find(|p| p.rsplit("::").next() == Some(&first))
+        {
+            return Some(prelude.split("::").map(String::from).collect());
+        }
+        // A name from a glob import, if there's only one to pick from
+        let mut globs = self.scopes[..depth]
+            .iter()
+            .enumerate()
+            .flat_map(|(i, scope)| scope.globs.iter().map(move |glob| (i, glob)));
+        match (globs.next(), globs.next()) {
+            (Some((i, glob)), None) => {
+                // The root of `use lib::*;` is looked up in the same scopes,
+                // where it must not be taken from the glob again
+                let key = (i, format!("{}::*", glob.join("::")));
+                if expanding.contains(&key) {
+                    return None;
+                }
+                expanding.push(key);
+                let canonical = self.canonicalize(i + 1, glob.clone(), expanding);
+                expanding.pop();
+                let mut canonical = canonical?;
+                canonical.push(first);
+                Some(canonical)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Module path of a file below `src/`, e.g. `a::b` for `src/a/b.rs` or
+/// `src/a/b/mod.rs`. Crate roots and files outside of `src/` are the root.
+fn file_module(file_name: &str) -> Vec<String> {
+    let path = Path::new(file_name).with_extension("");
+    let components: Vec<_> = path
+        .iter()
+        .map(|c| c.to_string_lossy().into_owned())
+        .collect();
+    let Some(src) = components.iter().rposition(|c| c == "src") else {
+        return Vec::new();
+    };
+    let mut module = components[src + 1..].to_vec();
+    match module.as_slice() {
+        [first, ..] if first == "bin" => module.clear(),
+        [root] if root == "lib" || root == "main" => module.clear(),
+        [.., last] if last == "mod" => {
+            module.pop();
+        }
+        _ => {}
+    }
+    module
+}
+
+#[test]
+fn test_resolve() {
+    let source = "
+        use std::io::{self, Write as IoWrite};
+        use futures::prelude::*;
+        use serde::Serialize;
+        use log::{self, log};
+        mod ops {
+            pub trait Add {}
+        }
+    ";
+    let file = syn::parse_file(source).unwrap();
+    let mut names = Names::new("src/a/mod.rs", &file.items);
+    let resolve = |names: &Names, path| {
+        let path: syn::Path = syn::parse_str(path).unwrap();
+        names
+            .resolve(&path)
+            .map(|(canonical, origin)| (canonical, Some(origin)))
+            .unwrap_or_default()
+    };
+    let path = |canonical: &str, origin| (canonical.to_string(), Some(origin));
+
+    assert_eq!(
+        resolve(&names, "io::Read"),
+        path("std::io::Read", PathOrigin::Std)
+    );
+    assert_eq!(
+        resolve(&names, "IoWrite"),
+        path("std::io::Write", PathOrigin::Std)
+    );
+    assert_eq!(
+        resolve(&names, "ops::Add"),
+        path("crate::a::ops::Add", PathOrigin::Local)
+    );
+    assert_eq!(
+        resolve(&names, "Stream"),
+        path("futures::prelude::Stream", PathOrigin::External)
+    );
+    assert_eq!(
+        resolve(&names, "Serialize"),
+        path("serde::Serialize", PathOrigin::External)
+    );
+    assert_eq!(
+        resolve(&names, "log::Log"),
+        path("log::Log", PathOrigin::External)
+    );
+    assert_eq!(
+        resolve(&names, "::rand::Rng"),
+        path("rand::Rng", PathOrigin::External)
+    );
+    assert_eq!(
+        resolve(&names, "Iterator"),
+        path("std::iter::Iterator", PathOrigin::Std)
+    );
+    assert_eq!(
+        resolve(&names, "super::Foo"),
+        path("crate::Foo", PathOrigin::Local)
+    );
+
+    let syn::Item::Mod(ops) = &file.items[4] else {
+        unreachable!()
+    };
+    names.enter(Some(&ops.ident), &ops.content.as_ref().unwrap().1);
+    assert_eq!(
+        resolve(&names, "Add"),
+        path("crate::a::ops::Add", PathOrigin::Local)
+    );
+    assert_eq!(
+        resolve(&names, "super::ops::Add"),
+        path("crate::a::ops::Add", PathOrigin::Local)
+    );
+    names.exit();
+    assert_eq!(names.local(&ops.ident), "crate::a::ops");
+
+    // Two globs to choose from
+    let file = syn::parse_file("use a::*; use b::*;").unwrap();
+    let names = Names::new("src/lib.rs", &file.items);
+    assert_eq!(resolve(&names, "Stream"), (String::new(), None));
+    assert_eq!(
+        resolve(&names, "Clone"),
+        path("std::clone::Clone", PathOrigin::Std)
+    );
+}
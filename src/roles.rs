@@ -0,0 +1,236 @@
+use crate::db::{add_column, Column, Database, SqlType};
+use crate::sql_enum;
+use crate::workspace::TargetKind;
+use anyhow::Error;
+use std::collections::HashSet;
+use std::path::Path;
+use syn::{Attribute, Meta, NestedMeta};
+
+sql_enum! {
+    /// What the code a row was collected from is for.
+    pub enum CodeRole {
+        Library,
+        Binary,
+        Test,
+        Bench,
+        Example,
+        Build,
+    }
+}
+
+/// The column which [`Logger::push_at`](crate::stats::Logger::push_at) fills in
+/// with the role of a row.
+pub const ROLE_COLUMN: Column = Column {
+    name: "role",
+    sql_type: CodeRole::SQL_TYPE,
+    since: None,
+    primary_key: false,
+    references: None,
+};
+
+/// Add [`ROLE_COLUMN`] to an existing table.
+pub fn add_role_column(db: &mut dyn Database, table: &str) -> Result<(), Error> {
+    add_column(db, table, &ROLE_COLUMN)
+}
+
+/// Files which are only compiled for tests because some module declares them
+/// with `#[cfg(test)] mod name;`.
+#[derive(Default)]
+pub struct TestModules {
+    files: HashSet<String>,
+    dirs: Vec<String>,
+}
+
+impl TestModules {
+    /// Look for test module declarations in the sources of a snapshot. This is
+    /// a plain text search, since most files are never parsed thanks to the
+    /// parse cache.
+    pub fn scan<'a>(files: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        let mut modules = TestModules::default();
+        for (file_name, source) in files {
+            let path = Path::new(file_name);
+            let mut dir = path.parent().unwrap_or(Path::new("")).to_owned();
+            match path.file_stem().and_then(|s| s.to_str()) {
+                Some("lib" | "main" | "mod") | None => {}
+                Some(stem) => dir.push(stem),
+            }
+            let mut rest = source;
+            while let Some(i) = rest.find("#[cfg(test)]") {
+                rest = &rest[i + "#[cfg(test)]".len()..];
+                if let Some(name) = declared_module(rest) {
+                    let module = dir.join(name);
+                    modules
+                        .files
+                        .insert(module.with_extension("rs").display().to_string());
+                    modules.dirs.push(format!("{}/", module.display()));
+                }
+            }
+        }
+        modules
+    }
+
+    fn contains(&self, file_name: &str) -> bool {
+        self.files.contains(file_name) || self.dirs.iter().any(|d| file_name.starts_with(d))
+    }
+}
+
+/// The name of the out-of-line module declared at the start of `source`, after
+/// skipping other attributes and the visibility.
+fn declared_module(source: &str) -> Option<&str> {
+    let mut rest = source.trim_start();
+    while let Some(attr) = rest.strip_prefix('#') {
+        let mut depth = 0;
+        let end = attr.find(|c| {
+            match c {
+                '[' => depth += 1,
+                ']' => depth -= 1,
+                _ => {}
+            }
+            depth == 0
+        })?;
+        rest = attr[end + 1..].trim_start();
+    }
+    if let Some(after) = rest.strip_prefix("pub") {
+        rest = after.trim_start();
+        if rest.starts_with('(') {
+            rest = rest[rest.find(')')? + 1..].trim_start();
+        }
+    }
+    let rest = rest.strip_prefix("mod")?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let rest = rest.trim_start();
+    let name_len = rest.find(|c: char| !(c.is_alphanumeric() || c == '_'))?;
+    let (name, after) = rest.split_at(name_len);
+    (!name.is_empty() && after.trim_start().starts_with(';')).then_some(name)
+}
+
+/// The role of a whole file, from the kind of the target it was located in
+/// or, outside of any known package, following Cargo's target auto-discovery.
+pub fn file_role(
+    file_name: &str,
+    target: Option<TargetKind>,
+    test_modules: &TestModules,
+) -> CodeRole {
+    if test_modules.contains(file_name) {
+        return CodeRole::Test;
+    }
+    if let Some(kind) = target {
+        return match kind {
+            TargetKind::Lib | TargetKind::ProcMacro => CodeRole::Library,
+            TargetKind::Bin => CodeRole::Binary,
+            TargetKind::Test => CodeRole::Test,
+            TargetKind::Bench => CodeRole::Bench,
+            TargetKind::Example => CodeRole::Example,
+            TargetKind::BuildScript => CodeRole::Build,
+        };
+    }
+    let parts: Vec<_> = Path::new(file_name)
+        .components()
+        .filter_map(|c| c.as_os_str().to_str())
+        .collect();
+    for (i, part) in parts.iter().enumerate() {
+        let rest = &parts[i + 1..];
+        match *part {
+            "src" => {
+                return match rest {
+                    ["main.rs"] | ["bin", ..] => CodeRole::Binary,
+                    _ => CodeRole::Library,
+                }
+            }
+            "tests" if !rest.is_empty() => return CodeRole::Test,
+            "benches" if !rest.is_empty() => return CodeRole::Bench,
+            "examples" if !rest.is_empty() => return CodeRole::Example,
+            _ => {}
+        }
+    }
+    match parts.last() {
+        Some(&"build.rs") => CodeRole::Build,
+        _ => CodeRole::Library,
+    }
+}
+
+/// The role of an item with `attrs` inside code of the `outer` role.
+pub fn item_role(attrs: &[Attribute], outer: CodeRole) -> CodeRole {
+    if matches!(outer, CodeRole::Test | CodeRole::Bench) {
+        return outer;
+    }
+    for attr in attrs {
+        let Some(name) = attr.path.segments.last() else {
+            continue;
+        };
+        if name.ident == "test" {
+            return CodeRole::Test;
+        }
+        if name.ident == "bench" {
+            return CodeRole::Bench;
+        }
+        if name.ident == "cfg" && attr.path.segments.len() == 1 {
+            let Ok(Meta::List(list)) = attr.parse_meta() else {
+                continue;
+            };
+            if list.nested.iter().any(requires_test) {
+                return CodeRole::Test;
+            }
+        }
+    }
+    outer
+}
+
+/// Whether a `cfg` predicate can only hold in test builds.
+fn requires_test(predicate: &NestedMeta) -> bool {
+    match predicate {
+        NestedMeta::Meta(Meta::Path(path)) => path.is_ident("test"),
+        NestedMeta::Meta(Meta::List(list)) if list.path.is_ident("all") => {
+            list.nested.iter().any(requires_test)
+        }
+        NestedMeta::Meta(Meta::List(list)) if list.path.is_ident("any") => {
+            !list.nested.is_empty() && list.nested.iter().all(requires_test)
+        }
+        _ => false,
+    }
+}
+
+#[test]
+fn test_file_role() {
+    let modules = TestModules::scan([
+        ("src/lib.rs", "mod a;\n#[cfg(test)]\nmod tests;"),
+        (
+            "src/a.rs",
+            "#[cfg(test)]\n#[allow(unused)]\npub(crate) mod unit ;",
+        ),
+    ]);
+    let role = |file_name| file_role(file_name, None, &modules);
+    assert_eq!(role("src/a.rs"), CodeRole::Library);
+    assert_eq!(role("src/test.rs"), CodeRole::Library);
+    assert_eq!(role("src/tests.rs"), CodeRole::Test);
+    assert_eq!(role("src/tests/helpers.rs"), CodeRole::Test);
+    assert_eq!(role("src/a/unit.rs"), CodeRole::Test);
+    assert_eq!(role("src/bin/tool.rs"), CodeRole::Binary);
+    assert_eq!(role("crates/foo/src/main.rs"), CodeRole::Binary);
+    assert_eq!(role("crates/foo/tests/it.rs"), CodeRole::Test);
+    assert_eq!(role("benches/speed.rs"), CodeRole::Bench);
+    assert_eq!(role("examples/demo.rs"), CodeRole::Example);
+    assert_eq!(role("build.rs"), CodeRole::Build);
+
+    // Targets from the manifest win over the file layout
+    let role = |file_name, kind| file_role(file_name, Some(kind), &modules);
+    assert_eq!(role("gen.rs", TargetKind::BuildScript), CodeRole::Build);
+    assert_eq!(role("src/cli.rs", TargetKind::Bin), CodeRole::Binary);
+    assert_eq!(role("src/tests.rs", TargetKind::Lib), CodeRole::Test);
+}
+
+#[test]
+fn test_item_role() {
+    let role = |item: &str| {
+        let item: syn::ItemFn = syn::parse_str(item).unwrap();
+        item_role(&item.attrs, CodeRole::Library)
+    };
+    assert_eq!(role("#[cfg(test)] fn f() {}"), CodeRole::Test);
+    assert_eq!(role("#[cfg(all(test, unix))] fn f() {}"), CodeRole::Test);
+    assert_eq!(role("#[cfg(any(test, unix))] fn f() {}"), CodeRole::Library);
+    assert_eq!(role("#[cfg(not(test))] fn f() {}"), CodeRole::Library);
+    assert_eq!(role("#[tokio::test] async fn f() {}"), CodeRole::Test);
+    assert_eq!(role("#[bench] fn f() {}"), CodeRole::Bench);
+}
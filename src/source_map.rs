@@ -0,0 +1,240 @@
+use crate::roles::{item_role, CodeRole};
+use proc_macro2::{LineColumn, Span};
+use quote::ToTokens;
+use std::ops::Range;
+use syn::spanned::Spanned;
+use syn::visit::{self, Visit};
+
+/// Byte offsets and enclosing items of one file, used to locate the rows
+/// collected from it.
+pub struct SourceMap<'a> {
+    text: &'a str,
+    /// Role of the code outside of any item
+    role: CodeRole,
+    /// Length of the byte order mark, which syn skips before parsing
+    bom: usize,
+    line_starts: Vec<usize>,
+    /// Byte range, path and role of every item, parents before their children
+    items: Vec<(Range<usize>, String, CodeRole)>,
+}
+
+/// Where a row's span is in its file.
+pub struct Location {
+    pub start: LineColumn,
+    pub end: LineColumn,
+    pub bytes: Range<usize>,
+    pub item_path: String,
+    pub role: CodeRole,
+}
+
+impl<'a> SourceMap<'a> {
+    pub fn new(source: &'a str, role: CodeRole) -> Self {
+        let (bom, text) = match source.strip_prefix('\u{feff}') {
+            Some(text) => ('\u{feff}'.len_utf8(), text),
+            None => (0, source),
+        };
+        let line_starts = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        SourceMap {
+            text,
+            role,
+            bom,
+            line_starts,
+            items: Vec::new(),
+        }
+    }
+
+    /// Record the items of `file`, which was parsed from this source.
+    pub fn add_items(&mut self, file: &syn::File) {
+        // e.g. `#![cfg(test)]` at the top of a test module
+        self.role = item_role(&file.attrs, self.role);
+        let mut collector = ItemCollector {
+            role: self.role,
+            map: self,
+            path: Vec::new(),
+        };
+        collector.visit_file(file);
+    }
+
+    fn offset(&self, position: LineColumn) -> usize {
+        let Some(&start) = self.line_starts.get(position.line.max(1) - 1) else {
+            return self.bom + self.text.len();
+        };
+        let column: usize = self.text[start..]
+            .chars()
+            .take(position.column)
+            .map(char::len_utf8)
+            .sum();
+        self.bom + start + column
+    }
+
+    pub fn locate(&self, span: Span) -> Location {
+        let (start, end) = (span.start(), span.end());
+        let bytes = self.offset(start)..self.offset(end);
+        let item = self
+            .items
+            .iter()
+            .rev()
+            .find(|(item, _, _)| item.start <= bytes.start && bytes.end <= item.end);
+        let (item_path, role) = match item {
+            Some((_, path, role)) => (path.clone(), *role),
+            None => (String::new(), self.role),
+        };
+        Location {
+            start,
+            end,
+            bytes,
+            item_path,
+            role,
+        }
+    }
+}
+
+struct ItemCollector<'m, 'a> {
+    map: &'m mut SourceMap<'a>,
+    path: Vec<String>,
+    role: CodeRole,
+}
+
+impl ItemCollector<'_, '_> {
+    fn enter(
+        &mut self,
+        span: Span,
+        segment: String,
+        attrs: &[syn::Attribute],
+        visit: impl FnOnce(&mut Self),
+    ) {
+        let outer = self.role;
+        self.role = item_role(attrs, outer);
+        self.path.push(segment);
+        let bytes = self.map.offset(span.start())..self.map.offset(span.end());
+        self.map
+            .items
+            .push((bytes, self.path.join("::"), self.role));
+        visit(self);
+        self.path.pop();
+        self.role = outer;
+    }
+}
+
+impl Visit<'_> for ItemCollector<'_, '_> {
+    fn visit_item(&mut self, node: &syn::Item) {
+        let (ident, attrs) = match node {
+            syn::Item::Const(i) => (&i.ident, &i.attrs),
+            syn::Item::Enum(i) => (&i.ident, &i.attrs),
+            syn::Item::Fn(i) => (&i.sig.ident, &i.attrs),
+            syn::Item::Mod(i) => (&i.ident, &i.attrs),
+            syn::Item::Static(i) => (&i.ident, &i.attrs),
+            syn::Item::Struct(i) => (&i.ident, &i.attrs),
+            syn::Item::Trait(i) => (&i.ident, &i.attrs),
+            syn::Item::TraitAlias(i) => (&i.ident, &i.attrs),
+            syn::Item::Type(i) => (&i.ident, &i.attrs),
+            syn::Item::Union(i) => (&i.ident, &i.attrs),
+            syn::Item::Macro(syn::ItemMacro {
+                ident: Some(ident),
+                attrs,
+                ..
+            }) => (ident, attrs),
+            syn::Item::Impl(i) => {
+                let self_ty = i.self_ty.to_token_stream();
+                let segment = match &i.trait_ {
+                    Some((_, path, _)) => {
+                        format!("<impl {} for {self_ty}>", path.to_token_stream())
+                    }
+                    None => format!("<impl {self_ty}>"),
+                };
+                return self.enter(node.span(), segment, &i.attrs, |c| {
+                    visit::visit_item(c, node)
+                });
+            }
+            _ => return visit::visit_item(self, node),
+        };
+        self.enter(node.span(), ident.to_string(), attrs, |c| {
+            visit::visit_item(c, node)
+        });
+    }
+
+    fn visit_impl_item(&mut self, node: &syn::ImplItem) {
+        let (ident, attrs) = match node {
+            syn::ImplItem::Const(i) => (&i.ident, &i.attrs),
+            syn::ImplItem::Method(i) => (&i.sig.ident, &i.attrs),
+            syn::ImplItem::Type(i) => (&i.ident, &i.attrs),
+            _ => return visit::visit_impl_item(self, node),
+        };
+        self.enter(node.span(), ident.to_string(), attrs, |c| {
+            visit::visit_impl_item(c, node)
+        });
+    }
+
+    fn visit_trait_item(&mut self, node: &syn::TraitItem) {
+        let (ident, attrs) = match node {
+            syn::TraitItem::Const(i) => (&i.ident, &i.attrs),
+            syn::TraitItem::Method(i) => (&i.sig.ident, &i.attrs),
+            syn::TraitItem::Type(i) => (&i.ident, &i.attrs),
+            _ => return visit::visit_trait_item(self, node),
+        };
+        self.enter(node.span(), ident.to_string(), attrs, |c| {
+            visit::visit_trait_item(c, node)
+        });
+    }
+
+    fn visit_foreign_item(&mut self, node: &syn::ForeignItem) {
+        let (ident, attrs) = match node {
+            syn::ForeignItem::Fn(i) => (&i.sig.ident, &i.attrs),
+            syn::ForeignItem::Static(i) => (&i.ident, &i.attrs),
+            syn::ForeignItem::Type(i) => (&i.ident, &i.attrs),
+            _ => return visit::visit_foreign_item(self, node),
+        };
+        self.enter(node.span(), ident.to_string(), attrs, |c| {
+            visit::visit_foreign_item(c, node)
+        });
+    }
+}
+
+#[test]
+fn test_locate() {
+    let source = "\u{feff}mod a {\n    struct Foo;\n    impl Clone for Foo {\n        fn clone(&self) -> Self { let é = || Foo; é() }\n    }\n}\n";
+    let file = syn::parse_file(source).unwrap();
+    let mut map = SourceMap::new(source, CodeRole::Library);
+    map.add_items(&file);
+
+    let syn::Item::Mod(module) = &file.items[0] else {
+        unreachable!()
+    };
+    let syn::Item::Impl(imp) = &module.content.as_ref().unwrap().1[1] else {
+        unreachable!()
+    };
+    let syn::ImplItem::Method(method) = &imp.items[0] else {
+        unreachable!()
+    };
+    let syn::Stmt::Local(local) = &method.block.stmts[0] else {
+        unreachable!()
+    };
+    let closure = source.find("|| Foo").unwrap();
+    let location = map.locate(local.init.as_ref().unwrap().1.span());
+    assert_eq!(location.bytes, closure..closure + "|| Foo".len());
+    assert_eq!((location.start.line, location.start.column), (4, 42));
+    assert_eq!(location.item_path, "a::<impl Clone for Foo>::clone");
+    assert_eq!(location.role, CodeRole::Library);
+
+    // Offsets count the byte order mark, which syn skips
+    let location = map.locate(module.span());
+    assert_eq!(location.bytes.start, '\u{feff}'.len_utf8());
+    assert_eq!(location.item_path, "a");
+}
+
+#[test]
+fn test_item_roles() {
+    let source = "fn f() {}\n#[cfg(test)]\nmod tests {\n    fn helper() {}\n}\n";
+    let file = syn::parse_file(source).unwrap();
+    let mut map = SourceMap::new(source, CodeRole::Library);
+    map.add_items(&file);
+
+    assert_eq!(map.locate(file.items[0].span()).role, CodeRole::Library);
+    let syn::Item::Mod(module) = &file.items[1] else {
+        unreachable!()
+    };
+    let helper = &module.content.as_ref().unwrap().1[0];
+    assert_eq!(map.locate(helper.span()).role, CodeRole::Test);
+}
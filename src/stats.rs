@@ -1,7 +1,25 @@
-use postgres::{NoTls, Transaction};
-use std::env::var;
+use crate::db::{
+    add_columns, create_row_table, create_table, migrate, Database, Migration, SchemaMigration,
+    SqlRow, Table, ToValue,
+};
+use crate::filter::{ExcludedFile, ExclusionReason};
+use crate::roles::CodeRole;
+use crate::source_map::SourceMap;
+use crate::workspace::{
+    DependencyKind, DependencySource, ManifestDependency, ManifestFeature, PackageManifest,
+    SourceFile, TargetKind,
+};
+use crate::{sql_enum, sql_row};
+use anyhow::Error;
+use proc_macro2::Span;
 use std::fs;
+use std::mem;
 use std::path::Path;
+use syn::{
+    spanned::Spanned,
+    visit::{self, Visit},
+};
+use tracing::trace;
 use uuid::Uuid;
 
 pub mod async_code;
@@ -10,9 +28,10 @@ pub mod traits;
 pub mod unsafe_code;
 
 pub struct Logger<'a, 'db> {
-    pub db: &'a mut Transaction<'db>,
+    pub db: &'a mut (dyn Database + 'db),
     pub file_name: &'a str,
     pub version_id: Uuid,
+    pub source_map: &'a SourceMap<'a>,
 }
 
 impl<'a, 'db> Logger<'a, 'db> {
@@ -21,65 +40,384 @@ impl<'a, 'db> Logger<'a, 'db> {
             db: self.db,
             file_name: self.file_name,
             version_id: self.version_id,
+            source_map: self.source_map,
         }
     }
+
+    /// Insert a row tagged with the current file and version.
+    pub fn insert(&mut self, table: &str, row: &[(&str, &dyn ToValue)]) {
+        let mut row = row.to_vec();
+        row.push(("file_name", &self.file_name));
+        row.push(("version_id", &self.version_id));
+        self.db.insert(table, &row).unwrap();
+    }
+
+    pub fn push<R: SqlRow>(&mut self, row: &R) {
+        trace!(row = ?row);
+        self.insert(R::TABLE, &row.columns());
+    }
+
+    /// Push a row along with the [`LOCATION_COLUMNS`](crate::db::LOCATION_COLUMNS)
+    /// and the role of the code at `span`.
+    pub fn push_at<R: SqlRow>(&mut self, row: &R, span: Span) {
+        trace!(row = ?row);
+        let location = self.source_map.locate(span);
+        let mut columns = row.columns();
+        columns.extend([
+            ("start_line", &location.start.line as &dyn ToValue),
+            ("start_column", &location.start.column),
+            ("end_line", &location.end.line),
+            ("end_column", &location.end.column),
+            ("start_byte", &location.bytes.start),
+            ("end_byte", &location.bytes.end),
+            ("item_path", &location.item_path),
+            ("role", &location.role),
+        ]);
+        self.insert(R::TABLE, &columns);
+    }
+}
+
+sql_enum! {
+    pub enum FailureKind {
+        Read,
+        Parse,
+    }
 }
 
-pub fn global_init(tx: &mut Transaction) {
-    tx.batch_execute(
-        r#"CREATE TABLE versions (
-                id UUID PRIMARY KEY,
-                crate_name TEXT,
-                date_str TEXT,
-                line_count_rust INT
-        );"#,
-    )
-    .unwrap();
+sql_row! {
+    #[table = "parse_failures"]
+    /// A file of a version which could not be analyzed.
+    pub struct ParseFailure {
+        pub kind: FailureKind,
+        pub line_number: Option<usize>,
+        pub message: String,
+        // Rust code lines of the file which are missing from the analysis
+        pub line_count: usize,
+        #[since = 5]
+        pub role: CodeRole,
+    }
+}
+
+sql_enum! {
+    /// The kind of item a `Function` row of the `unsafe_code` and `async_code`
+    /// runners was collected from.
+    pub enum FnItemKind {
+        FreeFn,
+        InherentMethod,
+        TraitImplMethod,
+        TraitMethod,
+    }
+}
+
+/// A function item, as passed to [`FnItemVisitor::visit_fn`].
+#[derive(Clone, Copy)]
+pub enum FnItem<'ast> {
+    Free(&'ast syn::ItemFn),
+    ImplMethod(&'ast syn::ImplItemMethod),
+    TraitMethod(&'ast syn::TraitItemMethod),
+}
+
+impl<'ast> FnItem<'ast> {
+    pub fn sig(self) -> &'ast syn::Signature {
+        match self {
+            FnItem::Free(node) => &node.sig,
+            FnItem::ImplMethod(node) => &node.sig,
+            FnItem::TraitMethod(node) => &node.sig,
+        }
+    }
+
+    pub fn span(self) -> Span {
+        match self {
+            FnItem::Free(node) => node.span(),
+            FnItem::ImplMethod(node) => node.span(),
+            FnItem::TraitMethod(node) => node.span(),
+        }
+    }
+
+    /// Walk the item with `v`, like the default `Visit` methods would.
+    pub fn visit(self, v: &mut impl Visit<'ast>) {
+        match self {
+            FnItem::Free(node) => visit::visit_item_fn(v, node),
+            FnItem::ImplMethod(node) => visit::visit_impl_item_method(v, node),
+            FnItem::TraitMethod(node) => visit::visit_trait_item_method(v, node),
+        }
+    }
+}
+
+/// A visitor which handles every function item in one place, along with its
+/// [`FnItemKind`]. Its `Visit` impl forwards the function items to the
+/// `visit_*` functions below.
+pub trait FnItemVisitor<'ast>: Visit<'ast> {
+    /// Whether the items being visited are in a trait impl.
+    fn in_trait_impl(&mut self) -> &mut bool;
+
+    fn visit_fn(&mut self, item: FnItem<'ast>, item_kind: FnItemKind);
+}
+
+pub fn visit_item_fn<'ast>(v: &mut impl FnItemVisitor<'ast>, node: &'ast syn::ItemFn) {
+    v.visit_fn(FnItem::Free(node), FnItemKind::FreeFn);
+}
+
+pub fn visit_item_impl<'ast>(v: &mut impl FnItemVisitor<'ast>, node: &'ast syn::ItemImpl) {
+    let outer = mem::replace(v.in_trait_impl(), node.trait_.is_some());
+    visit::visit_item_impl(v, node);
+    *v.in_trait_impl() = outer;
+}
+
+pub fn visit_impl_item_method<'ast>(
+    v: &mut impl FnItemVisitor<'ast>,
+    node: &'ast syn::ImplItemMethod,
+) {
+    let item_kind = if *v.in_trait_impl() {
+        FnItemKind::TraitImplMethod
+    } else {
+        FnItemKind::InherentMethod
+    };
+    v.visit_fn(FnItem::ImplMethod(node), item_kind);
+}
+
+pub fn visit_trait_item_method<'ast>(
+    v: &mut impl FnItemVisitor<'ast>,
+    node: &'ast syn::TraitItemMethod,
+) {
+    v.visit_fn(FnItem::TraitMethod(node), FnItemKind::TraitMethod);
+}
+
+sql_row! {
+    #[table = "language_stats"]
+    /// Lines of one language in a version, as counted by tokei.
+    pub struct LanguageStats {
+        pub language: String,
+        pub files: usize,
+        pub code: usize,
+        pub comments: usize,
+        pub blanks: usize,
+        #[references = "versions(id)"]
+        pub version_id: Uuid,
+    }
+}
+
+sql_row! {
+    #[table = "versions"]
+    /// A crate at one date, whose files are tagged with its `id`.
+    pub struct Version {
+        #[primary_key]
+        pub id: Uuid,
+        pub crate_name: String,
+        pub date_str: String,
+        pub line_count_rust: usize,
+        #[since = 2]
+        pub line_count_included: usize,
+        #[since = 4]
+        #[references = "runs(id)"]
+        pub run_id: Uuid,
+        #[since = 10]
+        pub commit_id: String,
+        #[since = 10]
+        pub commit_time: String,
+    }
+}
+
+sql_row! {
+    #[table = "runs"]
+    /// Who ran which build of crate-stats with which arguments.
+    pub struct Run {
+        #[primary_key]
+        pub id: Uuid,
+        pub git_hash: String,
+        pub args: String,
+        pub hostname: String,
+        pub started_at: String,
+        pub finished_at: Option<String>,
+    }
+}
+
+sql_row! {
+    #[table = "run_runners"]
+    /// A runner which took part in a run, with the versions of its schema and logic.
+    pub struct RunRunner {
+        #[references = "runs(id)"]
+        pub run_id: Uuid,
+        pub runner: String,
+        pub schema_version: usize,
+        pub logic_version: usize,
+    }
+}
+
+sql_enum! {
+    pub enum RunPhase {
+        Open,
+        Checkout,
+        Read,
+        Analyze,
+        File,
+        Commit,
+    }
+}
+
+sql_row! {
+    #[table = "run_errors"]
+    /// A panic which was caught, rolling back whatever it interrupted.
+    pub struct RunError {
+        pub crate_name: String,
+        pub phase: RunPhase,
+        pub date_str: Option<String>,
+        pub file_name: Option<String>,
+        pub message: String,
+        #[since = 4]
+        #[references = "runs(id)"]
+        pub run_id: Uuid,
+    }
+}
+
+const MIGRATIONS: &[Migration] = &[
+    |db| {
+        db.batch_execute(
+            r#"CREATE TABLE versions (
+                    id UUID PRIMARY KEY,
+                    crate_name TEXT,
+                    date_str TEXT,
+                    line_count_rust INT
+            );"#,
+        )
+    },
+    |db| {
+        FailureKind::init(db)?;
+        create_table::<ParseFailure>(db)?;
+        add_columns::<Version>(db, 2)?;
+        db.batch_execute(
+            r#"CREATE VIEW version_coverage AS
+                SELECT v.id AS version_id,
+                    CAST(v.line_count_included - COALESCE(SUM(f.line_count), 0) AS REAL)
+                        / NULLIF(v.line_count_rust, 0) AS coverage
+                FROM versions v
+                LEFT JOIN parse_failures f ON f.version_id = v.id
+                GROUP BY v.id;"#,
+        )
+    },
+    |db| {
+        RunPhase::init(db)?;
+        db.batch_execute(
+            r#"CREATE TABLE run_errors (
+                    crate_name TEXT,
+                    phase "RunPhase",
+                    date_str TEXT,
+                    file_name TEXT,
+                    message TEXT
+            );"#,
+        )
+    },
+    |db| {
+        create_row_table::<Run>(db)?;
+        create_row_table::<RunRunner>(db)?;
+        add_columns::<Version>(db, 4)?;
+        add_columns::<RunError>(db, 4)
+    },
+    |db| {
+        CodeRole::init(db)?;
+        add_columns::<ParseFailure>(db, 5)
+    },
+    |db| {
+        ExclusionReason::init(db)?;
+        create_table::<ExcludedFile>(db)
+    },
+    |db| {
+        TargetKind::init(db)?;
+        create_table::<SourceFile>(db)
+    },
+    |db| {
+        DependencyKind::init(db)?;
+        DependencySource::init(db)?;
+        create_table::<PackageManifest>(db)?;
+        create_table::<ManifestFeature>(db)?;
+        create_table::<ManifestDependency>(db)
+    },
+    |db| {
+        add_columns::<SourceFile>(db, 9)?;
+        create_row_table::<LanguageStats>(db)?;
+        db.batch_execute("CREATE INDEX language_stats_version_index ON language_stats(version_id);")
+    },
+    |db| add_columns::<Version>(db, 10),
+];
+
+/// The tables of [`MIGRATIONS`] once they all ran.
+const TABLES: &[Table] = &[
+    Table::of::<SchemaMigration>(),
+    Table::of::<Version>(),
+    Table::created::<ParseFailure>(),
+    Table::of::<RunError>(),
+    Table::of::<Run>(),
+    Table::of::<RunRunner>(),
+    Table::created::<ExcludedFile>(),
+    Table::created::<SourceFile>(),
+    Table::created::<PackageManifest>(),
+    Table::created::<ManifestFeature>(),
+    Table::created::<ManifestDependency>(),
+    Table::of::<LanguageStats>(),
+];
+
+/// Every table which [`init`] creates for `runners`.
+pub fn tables(runners: &[Runner]) -> Vec<Table> {
+    TABLES
+        .iter()
+        .chain(runners.iter().flat_map(|run| run.tables))
+        .copied()
+        .collect()
+}
+
+/// Bring the `versions` table and the tables of each runner up to date.
+pub fn init(tx: &mut dyn Database, runners: &[Runner]) -> Result<(), Error> {
+    migrate(tx, "versions", MIGRATIONS)?;
+    for run in runners {
+        migrate(tx, run.name, run.migrations)?;
+    }
+    Ok(())
 }
 
 #[derive(Clone, Copy)]
 pub struct Runner {
-    pub init: fn(db: &mut Transaction),
+    pub name: &'static str,
+    /// Bump whenever the rows collected from the same source change
+    pub version: usize,
+    pub tables: &'static [Table],
+    /// Creates and updates this runner's tables, never edit a released step
+    pub migrations: &'static [Migration],
     pub collect: fn(file: &syn::File, log: Logger),
 }
 
 impl Runner {
-    #[allow(unused)]
-    pub fn collect_mock(&self, name: &str) {
-        let mut args = postgres::Config::default();
-        args.dbname("crate-stats-test");
-        args.host("localhost");
-        if let Ok(user) = var("USER") {
-            args.user(&user);
-        }
-        let mut cli = args.connect(NoTls).unwrap();
-        let mut tx = cli.transaction().unwrap();
-        global_init(&mut tx);
-        (self.init)(&mut tx);
+    #[cfg(test)]
+    pub fn collect_mock(&self, name: &str) -> crate::db::MemoryDatabase {
+        let mut db = crate::db::MemoryDatabase::default();
+        init(&mut db, &[*self]).unwrap();
 
         let version_id = Uuid::new_v4();
 
-        tx.execute(
-            r"INSERT INTO versions (id, crate_name, date_str) VALUES ($1, $2, $3)",
-            &[&version_id, &name, &""],
+        db.insert(
+            "versions",
+            &[
+                ("id", &version_id),
+                ("crate_name", &name),
+                ("date_str", &""),
+            ],
         )
         .unwrap();
 
         assert!(
-            self.collect_path(
-                format!("./mocks/{name}.rs"),
-                Logger {
-                    db: &mut tx,
-                    file_name: "",
-                    version_id,
-                },
-            ),
+            self.collect_path(format!("./mocks/{name}.rs"), &mut db, "", version_id),
             "could not parse mock"
         );
-        tx.rollback().unwrap();
+        db
     }
 
-    pub fn collect_path(&self, path: impl AsRef<Path>, log: Logger) -> bool {
+    #[allow(unused)]
+    pub fn collect_path(
+        &self,
+        path: impl AsRef<Path>,
+        db: &mut dyn Database,
+        file_name: &str,
+        version_id: Uuid,
+    ) -> bool {
         let source = match fs::read_to_string(&path) {
             Ok(source) => source,
             Err(err) => {
@@ -99,6 +437,14 @@ impl Runner {
                 return false;
             }
         };
+        let mut source_map = SourceMap::new(&source, CodeRole::Library);
+        source_map.add_items(&file);
+        let log = Logger {
+            db,
+            file_name,
+            version_id,
+            source_map: &source_map,
+        };
         (self.collect)(&file, log);
         true
     }
@@ -1,12 +1,11 @@
-use crate::sql_enum;
-use quote::ToTokens;
+use crate::db::{add_columns, add_location_columns, create_table, SqlRow, Table};
+use crate::roles::add_role_column;
+use crate::stats::{self, FnItem, FnItemKind, FnItemVisitor};
+use crate::{sql_enum, sql_row};
 use syn::{
     spanned::Spanned,
     visit::{self, Visit},
 };
-use tracing::trace;
-#[cfg(test)]
-use tracing_test::traced_test;
 
 sql_enum! {
     enum AsyncCodeType {
@@ -15,49 +14,71 @@ sql_enum! {
     }
 }
 
-#[derive(Default, Debug)]
-struct CallParamList {
-    params: Vec<String>,
+sql_row! {
+    #[table = "async_code"]
+    pub struct Row {
+        async_code_type: AsyncCodeType,
+        block_count: Option<usize>,
+        first_line_number: usize,
+        last_line_number: usize,
+        outermost: bool,
+        #[since = 4]
+        item_kind: Option<FnItemKind>,
+    }
 }
 
-impl Visit<'_> for CallParamList {
-    fn visit_generic_argument(&mut self, node: &syn::GenericArgument) {
-        match node {
-            syn::GenericArgument::Type(t) => self.params.push(t.to_token_stream().to_string()),
-            _ => {}
-        }
+impl<'ast> FnItemVisitor<'ast> for Stats<'_, '_> {
+    fn in_trait_impl(&mut self) -> &mut bool {
+        &mut self.in_trait_impl
     }
-}
 
-impl Visit<'_> for Stats<'_, '_> {
-    fn visit_item_fn(&mut self, node: &syn::ItemFn) {
+    /// Count the async blocks of a function, and record it if it is an
+    /// `async fn`.
+    fn visit_fn(&mut self, item: FnItem<'ast>, item_kind: FnItemKind) {
         let mut child = Stats {
             log: self.log.fork(),
             count: 0,
             outermost: false,
+            in_trait_impl: false,
         };
-        visit::visit_item_fn(&mut child, node);
+        item.visit(&mut child);
 
-        if node.sig.asyncness.is_none() {
+        if item.sig().asyncness.is_none() {
             return;
         }
 
         let count = child.count;
+        let span = item.span();
 
-        self.log.db.execute(
-            r"INSERT INTO async_code (async_code_type, block_count, file_name, first_line_number, last_line_number, outermost, version_id)
-            VALUES                   ($1,              $2,          $3,        $4,                $5,               $6,        $7)",
-            &[
-                &AsyncCodeType::Function,
-                &(count as i32),
-                &self.log.file_name,
-                &(node.span().start().line as i32),
-                &(node.span().end().line as i32),
-                &self.outermost,
-                &self.log.version_id],
-        ).unwrap();
-
-        trace!(count = count, ty = "Function", outermost = self.outermost);
+        self.log.push_at(
+            &Row {
+                async_code_type: AsyncCodeType::Function,
+                block_count: Some(count),
+                first_line_number: span.start().line,
+                last_line_number: span.end().line,
+                outermost: self.outermost,
+                item_kind: Some(item_kind),
+            },
+            span,
+        );
+    }
+}
+
+impl<'ast> Visit<'ast> for Stats<'_, '_> {
+    fn visit_item_fn(&mut self, node: &'ast syn::ItemFn) {
+        stats::visit_item_fn(self, node);
+    }
+
+    fn visit_item_impl(&mut self, node: &'ast syn::ItemImpl) {
+        stats::visit_item_impl(self, node);
+    }
+
+    fn visit_impl_item_method(&mut self, node: &'ast syn::ImplItemMethod) {
+        stats::visit_impl_item_method(self, node);
+    }
+
+    fn visit_trait_item_method(&mut self, node: &'ast syn::TraitItemMethod) {
+        stats::visit_trait_item_method(self, node);
     }
 
     fn visit_expr_async(&mut self, node: &syn::ExprAsync) {
@@ -65,25 +86,23 @@ impl Visit<'_> for Stats<'_, '_> {
             log: self.log.fork(),
             count: 0,
             outermost: false,
+            in_trait_impl: false,
         };
         visit::visit_expr_async(&mut child, node);
         self.count += child.count;
         self.count += 1;
 
-        self.log.db.execute(
-            r"INSERT INTO async_code (async_code_type, block_count, file_name, first_line_number, last_line_number, outermost, version_id)
-            VALUES                   ($1,              $2,          $3,        $4,                $5,               $6,        $7)",
-            &[
-                &AsyncCodeType::Block,
-                &None::<i32>,
-                &self.log.file_name,
-                &(node.span().start().line as i32),
-                &(node.span().end().line as i32),
-                &self.outermost,
-                &self.log.version_id],
-        ).unwrap();
-
-        trace!(ty = "Block", outermost = self.outermost);
+        self.log.push_at(
+            &Row {
+                async_code_type: AsyncCodeType::Block,
+                block_count: None,
+                first_line_number: node.span().start().line,
+                last_line_number: node.span().end().line,
+                outermost: self.outermost,
+                item_kind: None,
+            },
+            node.span(),
+        );
     }
 }
 
@@ -91,44 +110,82 @@ struct Stats<'log, 'db> {
     log: super::Logger<'log, 'db>,
     count: usize,
     outermost: bool,
+    in_trait_impl: bool,
 }
 
 pub const RUNNER: super::Runner = super::Runner {
+    name: "async_code",
+    version: 2,
+    tables: &[Table::located::<Row>()],
     collect: |file, log| {
         visit::visit_file(
             &mut Stats {
                 log,
                 count: 0,
                 outermost: true,
+                in_trait_impl: false,
             },
             file,
         )
     },
-    init: |db| {
-        AsyncCodeType::init(db);
-        db.batch_execute(
-            r#"
-            CREATE TABLE async_code (
-                async_code_type "AsyncCodeType",
-                block_count INT,
-                first_line_number INT,
-                last_line_number INT,
-                outermost BOOL,
-                file_name TEXT,
-                version_id UUID references versions(id)
-            );
-            CREATE INDEX async_code_version_index ON async_code(version_id);
-        "#,
-        )
-        .unwrap();
-    },
+    migrations: &[
+        |db| {
+            AsyncCodeType::init(db)?;
+            create_table::<Row>(db)
+        },
+        |db| add_location_columns(db, Row::TABLE),
+        |db| add_role_column(db, Row::TABLE),
+        |db| {
+            FnItemKind::init(db)?;
+            add_columns::<Row>(db, 4)
+        },
+    ],
 };
 
 #[test]
-#[traced_test]
 fn test_async_fn() {
-    RUNNER.collect_mock("async_fn");
-    assert!(logs_contain(r#"ty="Block" outermost=false"#));
-    assert!(!logs_contain(r#"ty="Block" outermost=true"#));
-    assert!(logs_contain(r#"count=4 ty="Function" outermost=true"#));
+    let db = RUNNER.collect_mock("async_fn");
+    assert!(db.contains(
+        "async_code",
+        &[
+            ("async_code_type", &AsyncCodeType::Block),
+            ("outermost", &false),
+        ],
+    ));
+    assert!(!db.contains(
+        "async_code",
+        &[
+            ("async_code_type", &AsyncCodeType::Block),
+            ("outermost", &true),
+        ],
+    ));
+    assert!(db.contains(
+        "async_code",
+        &[
+            ("async_code_type", &AsyncCodeType::Function),
+            ("block_count", &4),
+            ("outermost", &true),
+            ("item_kind", &FnItemKind::FreeFn),
+        ],
+    ));
+}
+
+#[test]
+fn test_async_methods() {
+    let db = RUNNER.collect_mock("async_methods");
+    let function = |item_kind, block_count| {
+        db.contains(
+            "async_code",
+            &[
+                ("async_code_type", &AsyncCodeType::Function),
+                ("item_kind", &item_kind),
+                ("block_count", &block_count),
+            ],
+        )
+    };
+    assert!(function(FnItemKind::InherentMethod, 1));
+    assert!(function(FnItemKind::TraitMethod, 0));
+    assert!(function(FnItemKind::TraitMethod, 2));
+    assert!(function(FnItemKind::TraitImplMethod, 0));
+    assert!(!db.contains("async_code", &[("item_kind", &FnItemKind::FreeFn)]));
 }
@@ -1,15 +1,26 @@
+use crate::db::{add_location_columns, create_table, SqlRow, Table};
+use crate::roles::add_role_column;
+use crate::sql_row;
 use syn::spanned::Spanned;
 use syn::visit::{self, Visit};
 
+sql_row! {
+    #[table = "closures"]
+    pub struct Row {
+        line_number: usize,
+        is_try_like: bool,
+    }
+}
+
 impl Visit<'_> for Stats<'_, '_> {
     fn visit_expr_closure(&mut self, node: &syn::ExprClosure) {
-        self.log
-            .db
-            .execute(
-                r"INSERT INTO closures (file_name, line_number, is_try_like, version_id) VALUES ($1, $2, $3, $4)",
-                &[&self.log.file_name, &(node.span().start().line as i32), &self.is_in_call, &self.log.version_id],
-            )
-            .unwrap();
+        self.log.push_at(
+            &Row {
+                line_number: node.span().start().line,
+                is_try_like: self.is_in_call,
+            },
+            node.span(),
+        );
 
         let mut child = Stats {
             log: self.log.fork(),
@@ -41,6 +52,9 @@ struct Stats<'log, 'db> {
 }
 
 pub const RUNNER: super::Runner = super::Runner {
+    name: "closures",
+    version: 1,
+    tables: &[Table::located::<Row>()],
     collect: |file, log| {
         visit::visit_file(
             &mut Stats {
@@ -50,18 +64,9 @@ pub const RUNNER: super::Runner = super::Runner {
             file,
         )
     },
-    init: |db| {
-        db.batch_execute(
-            r#"
-            CREATE TABLE closures (
-                file_name TEXT,
-                line_number INT,
-                is_try_like BOOLEAN,
-                version_id UUID references versions(id)
-            );
-            CREATE INDEX closures_version_index ON closures(version_id);
-        "#,
-        )
-        .unwrap();
-    },
+    migrations: &[
+        |db| create_table::<Row>(db),
+        |db| add_location_columns(db, Row::TABLE),
+        |db| add_role_column(db, Row::TABLE),
+    ],
 };
@@ -1,11 +1,13 @@
-use crate::sql_enum;
+use crate::db::{add_columns, add_location_columns, create_table, SqlRow, Table};
+use crate::names::{Names, PathOrigin};
+use crate::roles::add_role_column;
+use crate::{sql_enum, sql_row};
 use proc_macro2::Span;
+use quote::ToTokens;
+use syn::punctuated::Punctuated;
 use syn::spanned::Spanned;
 use syn::visit::{self, Visit};
-use tracing::trace;
-
-#[cfg(test)]
-use tracing_test::traced_test;
+use syn::Token;
 
 sql_enum! {
     enum SyntaxType {
@@ -14,6 +16,8 @@ sql_enum! {
         TypeImpl,
         TypeDyn,
         WhereClause,
+        GenericParam,
+        AssocTypeBound,
     }
 }
 
@@ -21,169 +25,302 @@ sql_enum! {
     enum PositionType {
         Argument,
         Return,
+        Field,
+        Let,
+        TypeAlias,
+        Const,
+        Static,
+        ImplSelf,
+        Cast,
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct Row {
-    syntax: SyntaxType,
-    position: Option<PositionType>,
-    generic_count: usize,
-    at_count: usize,
-    gat_count: Option<usize>,
-    trait_name: String,
-    trait_bounds_count: usize,
-    lifetime_bounds_count: usize,
+sql_row! {
+    #[table = "traits"]
+    pub struct Row {
+        syntax: SyntaxType,
+        position: Option<PositionType>,
+        at_count: usize,
+        gat_count: Option<usize>,
+        generic_count: usize,
+        trait_bounds_count: usize,
+        lifetime_bounds_count: usize,
+        trait_name: String,
+        line_number: usize,
+        // Number of types the trait is nested in within its position
+        #[since = 5]
+        depth: Option<usize>,
+        // The path as written, e.g. `io::Write`
+        #[since = 6]
+        written_path: Option<String>,
+        // Best-effort path from the crate root, e.g. `std::io::Write`
+        #[since = 6]
+        canonical_path: Option<String>,
+        #[since = 6]
+        origin: Option<PathOrigin>,
+    }
+}
+
+sql_enum! {
+    enum BoundKind {
+        Impl,
+        Dyn,
+        /// A type parameter of an enclosing item
+        Generic,
+        Other,
+    }
+}
+
+sql_row! {
+    #[table = "trait_bindings"]
+    pub struct Binding {
+        // `start_byte` of the `traits` row of the bound trait, which with the
+        // file and version is the key of `traits`
+        trait_start_byte: usize,
+        assoc_type: String,
+        bound_type: String,
+        bound_kind: BoundKind,
+    }
 }
 
 #[derive(Default, Debug)]
-struct TraitParamCounter {
+struct TraitParamCounter<'ast> {
     generic_count: usize,
-    at_count: usize,
+    /// Associated type, bound type and span of each binding, with the return
+    /// type of `Fn(A) -> B` bound to `Output`
+    bindings: Vec<(String, &'ast syn::Type, Span)>,
+    /// Bounds on associated types, like the `Clone` of `Iterator<Item: Clone>`
+    constraints: Vec<&'ast syn::Constraint>,
 }
 
-impl Visit<'_> for TraitParamCounter {
-    fn visit_generic_argument(&mut self, node: &syn::GenericArgument) {
+impl<'ast> Visit<'ast> for TraitParamCounter<'ast> {
+    fn visit_generic_argument(&mut self, node: &'ast syn::GenericArgument) {
         match node {
             syn::GenericArgument::Lifetime(_) => {}
             syn::GenericArgument::Type(_) => self.generic_count += 1,
             syn::GenericArgument::Const(_) => {}
-            syn::GenericArgument::Binding(_) => self.at_count += 1,
-            syn::GenericArgument::Constraint(_) => {}
+            syn::GenericArgument::Binding(b) => {
+                self.bindings.push((b.ident.to_string(), &b.ty, b.span()))
+            }
+            syn::GenericArgument::Constraint(c) => self.constraints.push(c),
         }
     }
 
-    fn visit_parenthesized_generic_arguments(&mut self, node: &syn::ParenthesizedGenericArguments) {
+    fn visit_parenthesized_generic_arguments(
+        &mut self,
+        node: &'ast syn::ParenthesizedGenericArguments,
+    ) {
         self.generic_count += node.inputs.len();
-        match node.output {
+        match &node.output {
             syn::ReturnType::Default => {}
-            syn::ReturnType::Type(_, _) => self.at_count += 1,
+            syn::ReturnType::Type(_, ty) => {
+                self.bindings
+                    .push(("Output".to_string(), ty, node.output.span()))
+            }
         }
     }
 }
 
-struct PositionalStats<'log, 'db> {
-    stats: Stats<'log, 'db>,
+struct PositionalStats<'a, 'log, 'db> {
+    stats: &'a mut Stats<'log, 'db>,
     position: PositionType,
+    depth: usize,
 }
 
-impl Visit<'_> for PositionalStats<'_, '_> {
-    fn visit_type_impl_trait(&mut self, node: &syn::TypeImplTrait) {
-        let trait_bounds_count = node
-            .bounds
-            .iter()
-            .filter(|b| matches!(b, syn::TypeParamBound::Trait(_)))
-            .count();
-        let lifetime_bounds_count = node.bounds.len() - trait_bounds_count;
-        for bound in &node.bounds {
-            // TODO: Deal with Lifetime
-            self.stats.collect_type_param_bound(
-                bound,
-                SyntaxType::TypeImpl,
-                Some(self.position),
-                trait_bounds_count,
-                lifetime_bounds_count,
-            )
+impl Visit<'_> for PositionalStats<'_, '_, '_> {
+    fn visit_type(&mut self, node: &syn::Type) {
+        match node {
+            // Parentheses and invisible groups don't nest, `impl` and `dyn`
+            // nest their bounds themselves
+            syn::Type::Paren(_)
+            | syn::Type::Group(_)
+            | syn::Type::ImplTrait(_)
+            | syn::Type::TraitObject(_) => visit::visit_type(self, node),
+            _ => {
+                self.depth += 1;
+                visit::visit_type(self, node);
+                self.depth -= 1;
+            }
         }
     }
 
+    fn visit_type_impl_trait(&mut self, node: &syn::TypeImplTrait) {
+        self.stats.collect_bounds(
+            &node.bounds,
+            SyntaxType::TypeImpl,
+            Some((self.position, self.depth)),
+        );
+        self.depth += 1;
+        visit::visit_type_impl_trait(self, node);
+        self.depth -= 1;
+    }
+
     fn visit_type_trait_object(&mut self, node: &syn::TypeTraitObject) {
-        let trait_bounds_count = node
-            .bounds
-            .iter()
-            .filter(|b| matches!(b, syn::TypeParamBound::Trait(_)))
-            .count();
-        let lifetime_bounds_count = node.bounds.len() - trait_bounds_count;
-        for bound in &node.bounds {
-            // TODO: Deal with Lifetime
-            self.stats.collect_type_param_bound(
-                bound,
-                SyntaxType::TypeDyn,
-                Some(self.position),
-                trait_bounds_count,
-                lifetime_bounds_count,
-            )
-        }
+        self.stats.collect_bounds(
+            &node.bounds,
+            SyntaxType::TypeDyn,
+            Some((self.position, self.depth)),
+        );
+        self.depth += 1;
+        visit::visit_type_trait_object(self, node);
+        self.depth -= 1;
     }
 }
 
 pub struct Stats<'log, 'db> {
     log: super::Logger<'log, 'db>,
+    names: Names,
+    /// Type parameters of the enclosing items
+    type_params: Vec<String>,
 }
 
-impl<'log, 'db> Stats<'log, 'db> {
-    pub fn push(&mut self, row: Row, span: Span) {
-        trace!(row = ?row);
-        self.log
-            .db
-            .execute(
-                "INSERT INTO traits
-                (syntax, position, at_count, gat_count, generic_count, trait_bounds_count, lifetime_bounds_count, trait_name, file_name, line_number, version_id)
-                VALUES
-                ($1,     $2,       $3,       $4,        $5,            $6,                $7,                    $8,         $9,        $10,         $11)",
-                &[
-                    &row.syntax,
-                    &row.position,
-                    &(row.at_count as i32),
-                    &(row.gat_count.map(|i| i as i32)),
-                    &(row.generic_count as i32),
-                    &(row.trait_bounds_count as i32),
-                    &(row.lifetime_bounds_count as i32),
-                    &row.trait_name,
-                    &self.log.file_name,
-                    &(span.start().line as i32),
-                    &self.log.version_id,
-                ],
-            )
-            .unwrap();
+impl Visit<'_> for Stats<'_, '_> {
+    fn visit_item_mod(&mut self, node: &syn::ItemMod) {
+        let Some((_, items)) = &node.content else {
+            return;
+        };
+        self.names.enter(Some(&node.ident), items);
+        visit::visit_item_mod(self, node);
+        self.names.exit();
+    }
+
+    fn visit_block(&mut self, node: &syn::Block) {
+        let items = node.stmts.iter().filter_map(|stmt| match stmt {
+            syn::Stmt::Item(item) => Some(item),
+            _ => None,
+        });
+        self.names.enter(None, items);
+        visit::visit_block(self, node);
+        self.names.exit();
+    }
+
+    fn visit_item_fn(&mut self, node: &syn::ItemFn) {
+        self.with_generics(&node.sig.generics, |stats| {
+            visit::visit_item_fn(stats, node)
+        })
+    }
+
+    fn visit_item_struct(&mut self, node: &syn::ItemStruct) {
+        self.with_generics(&node.generics, |stats| {
+            visit::visit_item_struct(stats, node)
+        })
+    }
+
+    fn visit_item_enum(&mut self, node: &syn::ItemEnum) {
+        self.with_generics(&node.generics, |stats| visit::visit_item_enum(stats, node))
+    }
+
+    fn visit_item_union(&mut self, node: &syn::ItemUnion) {
+        self.with_generics(&node.generics, |stats| visit::visit_item_union(stats, node))
     }
-}
 
-impl Visit<'_> for Stats<'_, '_> {
     fn visit_fn_arg(&mut self, node: &syn::FnArg) {
-        visit::visit_fn_arg(
-            &mut PositionalStats {
-                stats: Stats {
-                    log: self.log.fork(),
-                },
-                position: PositionType::Argument,
-            },
-            node,
-        )
+        visit::visit_fn_arg(&mut self.positional(PositionType::Argument), node)
     }
 
     fn visit_return_type(&mut self, node: &syn::ReturnType) {
-        visit::visit_return_type(
-            &mut PositionalStats {
-                stats: Stats {
-                    log: self.log.fork(),
-                },
-                position: PositionType::Return,
-            },
-            node,
-        )
+        visit::visit_return_type(&mut self.positional(PositionType::Return), node)
     }
 
-    fn visit_predicate_type(&mut self, node: &syn::PredicateType) {
-        let trait_bounds_count = node
-            .bounds
-            .iter()
-            .filter(|b| matches!(b, syn::TypeParamBound::Trait(_)))
-            .count();
-        let lifetime_bounds_count = node.bounds.len() - trait_bounds_count;
-        for bound in &node.bounds {
-            // TODO: Deal with Lifetime
-            self.collect_type_param_bound(
-                bound,
-                SyntaxType::WhereClause,
-                None,
-                trait_bounds_count,
-                lifetime_bounds_count,
-            )
+    fn visit_field(&mut self, node: &syn::Field) {
+        visit::visit_field(&mut self.positional(PositionType::Field), node)
+    }
+
+    fn visit_local(&mut self, node: &syn::Local) {
+        self.positional(PositionType::Let).visit_pat(&node.pat);
+        if let Some((_, init)) = &node.init {
+            self.visit_expr(init);
         }
     }
 
+    fn visit_item_type(&mut self, node: &syn::ItemType) {
+        self.with_generics(&node.generics, |stats| {
+            stats.visit_generics(&node.generics);
+            stats
+                .positional(PositionType::TypeAlias)
+                .visit_type(&node.ty);
+        })
+    }
+
+    fn visit_item_const(&mut self, node: &syn::ItemConst) {
+        self.positional(PositionType::Const).visit_type(&node.ty);
+        self.visit_expr(&node.expr);
+    }
+
+    fn visit_impl_item_const(&mut self, node: &syn::ImplItemConst) {
+        self.positional(PositionType::Const).visit_type(&node.ty);
+        self.visit_expr(&node.expr);
+    }
+
+    fn visit_trait_item_const(&mut self, node: &syn::TraitItemConst) {
+        self.positional(PositionType::Const).visit_type(&node.ty);
+        if let Some((_, default)) = &node.default {
+            self.visit_expr(default);
+        }
+    }
+
+    fn visit_item_static(&mut self, node: &syn::ItemStatic) {
+        self.positional(PositionType::Static).visit_type(&node.ty);
+        self.visit_expr(&node.expr);
+    }
+
+    fn visit_expr_cast(&mut self, node: &syn::ExprCast) {
+        self.visit_expr(&node.expr);
+        self.positional(PositionType::Cast).visit_type(&node.ty);
+    }
+
+    fn visit_predicate_type(&mut self, node: &syn::PredicateType) {
+        self.collect_bounds(&node.bounds, SyntaxType::WhereClause, None)
+    }
+
+    fn visit_type_param(&mut self, node: &syn::TypeParam) {
+        self.collect_bounds(&node.bounds, SyntaxType::GenericParam, None)
+    }
+
     fn visit_item_impl(&mut self, node: &syn::ItemImpl) {
+        self.with_generics(&node.generics, |stats| {
+            stats.visit_generics(&node.generics);
+            stats
+                .positional(PositionType::ImplSelf)
+                .visit_type(&node.self_ty);
+            stats.collect_impl_for(node);
+            for item in &node.items {
+                stats.visit_impl_item(item);
+            }
+        })
+    }
+
+    fn visit_impl_item_method(&mut self, node: &syn::ImplItemMethod) {
+        self.with_generics(&node.sig.generics, |stats| {
+            visit::visit_impl_item_method(stats, node)
+        })
+    }
+
+    fn visit_item_trait(&mut self, node: &syn::ItemTrait) {
+        self.collect_trait_def(node);
+        self.with_generics(&node.generics, |stats| {
+            stats.visit_generics(&node.generics);
+            for item in &node.items {
+                stats.visit_trait_item(item);
+            }
+        })
+    }
+
+    fn visit_trait_item_method(&mut self, node: &syn::TraitItemMethod) {
+        self.with_generics(&node.sig.generics, |stats| {
+            visit::visit_trait_item_method(stats, node)
+        })
+    }
+
+    fn visit_trait_item_type(&mut self, node: &syn::TraitItemType) {
+        self.collect_bounds(&node.bounds, SyntaxType::AssocTypeBound, None);
+        self.with_generics(&node.generics, |stats| stats.visit_generics(&node.generics))
+    }
+}
+
+impl<'log, 'db> Stats<'log, 'db> {
+    /// Record the trait of an `impl Trait for Type` block.
+    fn collect_impl_for(&mut self, node: &syn::ItemImpl) {
         let (_, path, _) = match &node.trait_ {
             Some(t) => t,
             None => return,
@@ -218,7 +355,7 @@ impl Visit<'_> for Stats<'_, '_> {
         )
     }
 
-    fn visit_item_trait(&mut self, node: &syn::ItemTrait) {
+    fn collect_trait_def(&mut self, node: &syn::ItemTrait) {
         let at_count = node
             .items
             .iter()
@@ -244,8 +381,8 @@ impl Visit<'_> for Stats<'_, '_> {
             })
             .count();
 
-        self.push(
-            Row {
+        self.log.push_at(
+            &Row {
                 syntax: SyntaxType::TraitDef,
                 position: None,
                 generic_count,
@@ -254,18 +391,80 @@ impl Visit<'_> for Stats<'_, '_> {
                 trait_name: node.ident.to_string(),
                 trait_bounds_count: 0,
                 lifetime_bounds_count: 0,
+                line_number: node.ident.span().start().line,
+                depth: None,
+                written_path: Some(node.ident.to_string()),
+                canonical_path: Some(self.names.local(&node.ident)),
+                origin: Some(PathOrigin::Local),
             },
             node.ident.span(),
         );
     }
-}
 
-impl Stats<'_, '_> {
+    /// Visit an item with its type parameters in scope.
+    fn with_generics(&mut self, generics: &syn::Generics, visit: impl FnOnce(&mut Self)) {
+        let len = self.type_params.len();
+        self.type_params
+            .extend(generics.type_params().map(|p| p.ident.to_string()));
+        visit(self);
+        self.type_params.truncate(len);
+    }
+
+    fn bound_kind(&self, ty: &syn::Type) -> BoundKind {
+        match ty {
+            syn::Type::Paren(ty) => self.bound_kind(&ty.elem),
+            syn::Type::Group(ty) => self.bound_kind(&ty.elem),
+            syn::Type::ImplTrait(_) => BoundKind::Impl,
+            syn::Type::TraitObject(_) => BoundKind::Dyn,
+            syn::Type::Path(ty)
+                if ty.qself.is_none()
+                    && ty
+                        .path
+                        .get_ident()
+                        .is_some_and(|ident| self.type_params.iter().any(|p| ident == p)) =>
+            {
+                BoundKind::Generic
+            }
+            _ => BoundKind::Other,
+        }
+    }
+
+    fn positional(&mut self, position: PositionType) -> PositionalStats<'_, 'log, 'db> {
+        PositionalStats {
+            stats: self,
+            position,
+            depth: 0,
+        }
+    }
+
+    fn collect_bounds(
+        &mut self,
+        bounds: &Punctuated<syn::TypeParamBound, Token![+]>,
+        syntax: SyntaxType,
+        position: Option<(PositionType, usize)>,
+    ) {
+        let trait_bounds_count = bounds
+            .iter()
+            .filter(|b| matches!(b, syn::TypeParamBound::Trait(_)))
+            .count();
+        let lifetime_bounds_count = bounds.len() - trait_bounds_count;
+        for bound in bounds {
+            // TODO: Deal with Lifetime
+            self.collect_type_param_bound(
+                bound,
+                syntax,
+                position,
+                trait_bounds_count,
+                lifetime_bounds_count,
+            )
+        }
+    }
+
     fn collect_type_param_bound(
         &mut self,
         bound: &syn::TypeParamBound,
         syntax: SyntaxType,
-        position: Option<PositionType>,
+        position: Option<(PositionType, usize)>,
         trait_bounds_count: usize,
         lifetime_bounds_count: usize,
     ) {
@@ -285,11 +484,12 @@ impl Stats<'_, '_> {
         )
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn collect_trait_path(
         &mut self,
         path: &syn::Path,
         syntax: SyntaxType,
-        position: Option<PositionType>,
+        position: Option<(PositionType, usize)>,
         base_at_count: usize,
         gat_count: Option<usize>,
         trait_bounds_count: usize,
@@ -304,121 +504,489 @@ impl Stats<'_, '_> {
 
         visit::visit_path(&mut counter, path);
 
-        self.push(
-            Row {
+        let written_path = path
+            .segments
+            .iter()
+            .map(|seg| seg.ident.to_string())
+            .collect::<Vec<_>>()
+            .join("::");
+        let written_path = match path.leading_colon {
+            Some(_) => format!("::{written_path}"),
+            None => written_path,
+        };
+        let (canonical_path, origin) = self.names.resolve(path).unzip();
+
+        self.log.push_at(
+            &Row {
                 syntax,
-                position,
+                position: position.map(|(position, _)| position),
                 generic_count: counter.generic_count,
-                at_count: counter.at_count + base_at_count,
+                at_count: counter.bindings.len() + base_at_count,
                 gat_count,
                 trait_name,
                 trait_bounds_count,
                 lifetime_bounds_count,
+                line_number: path.span().start().line,
+                depth: position.map(|(_, depth)| depth),
+                written_path: Some(written_path),
+                canonical_path,
+                origin,
             },
             path.span(),
         );
+
+        for constraint in counter.constraints {
+            self.collect_bounds(&constraint.bounds, SyntaxType::AssocTypeBound, None);
+        }
+
+        if counter.bindings.is_empty() {
+            return;
+        }
+        let trait_start_byte = self.log.source_map.locate(path.span()).bytes.start;
+        for (assoc_type, ty, span) in counter.bindings {
+            self.log.push_at(
+                &Binding {
+                    trait_start_byte,
+                    assoc_type,
+                    bound_type: ty.to_token_stream().to_string(),
+                    bound_kind: self.bound_kind(ty),
+                },
+                span,
+            );
+        }
     }
 }
 
 pub const RUNNER: super::Runner = super::Runner {
-    collect: |file, log| visit::visit_file(&mut Stats { log }, file),
-    init: |db| {
-        SyntaxType::init(db);
-        PositionType::init(db);
-        db.batch_execute(
-            r#"
-            CREATE TABLE traits (
-                syntax "SyntaxType",
-                position "PositionType",
-                at_count INT,
-                gat_count INT,
-                generic_count INT,
-                trait_bounds_count INT,
-                lifetime_bounds_count INT,
-                trait_name TEXT,
-                line_number INT,
-                file_name TEXT,
-                version_id UUID references versions(id)
-            );
-            CREATE INDEX traits_version_index ON traits(version_id);
-            CREATE INDEX traits_name_index ON traits(trait_name);
-        "#,
-        )
-        .unwrap();
+    name: "traits",
+    version: 6,
+    tables: &[Table::located::<Row>(), Table::located::<Binding>()],
+    collect: |file, log| {
+        let names = Names::new(log.file_name, &file.items);
+        let mut stats = Stats {
+            log,
+            names,
+            type_params: Vec::new(),
+        };
+        visit::visit_file(&mut stats, file)
     },
+    migrations: &[
+        |db| {
+            SyntaxType::init(db)?;
+            PositionType::init(db)?;
+            create_table::<Row>(db)?;
+            db.batch_execute("CREATE INDEX traits_name_index ON traits(trait_name);")
+        },
+        |db| add_location_columns(db, Row::TABLE),
+        |db| add_role_column(db, Row::TABLE),
+        |db| db.extend_enum("SyntaxType", &["GenericParam", "AssocTypeBound"]),
+        |db| {
+            db.extend_enum(
+                "PositionType",
+                &[
+                    "Field",
+                    "Let",
+                    "TypeAlias",
+                    "Const",
+                    "Static",
+                    "ImplSelf",
+                    "Cast",
+                ],
+            )?;
+            add_columns::<Row>(db, 5)
+        },
+        |db| {
+            PathOrigin::init(db)?;
+            add_columns::<Row>(db, 6)?;
+            db.batch_execute("CREATE INDEX traits_canonical_path_index ON traits(canonical_path);")
+        },
+        |db| {
+            BoundKind::init(db)?;
+            // Bindings refer to their trait by its key, the start of its path
+            // within a file, and are only checked on commit since batched
+            // inserts may reach `trait_bindings` first
+            db.batch_execute(
+                r#"CREATE UNIQUE INDEX traits_key_index
+                    ON traits(version_id, file_name, start_byte);
+                CREATE TABLE trait_bindings (
+                        trait_start_byte INT,
+                        assoc_type TEXT,
+                        bound_type TEXT,
+                        bound_kind "BoundKind",
+                        file_name TEXT,
+                        version_id UUID REFERENCES versions(id),
+                        FOREIGN KEY (version_id, file_name, trait_start_byte)
+                            REFERENCES traits(version_id, file_name, start_byte)
+                            DEFERRABLE INITIALLY DEFERRED
+                );
+                CREATE INDEX trait_bindings_version_index ON trait_bindings(version_id);"#,
+            )?;
+            add_location_columns(db, Binding::TABLE)?;
+            add_role_column(db, Binding::TABLE)
+        },
+    ],
 };
 
 #[test]
-#[traced_test]
 fn test_impl_for() {
-    RUNNER.collect_mock("impl_for");
-    assert!(logs_contain(
-        r#"row=Row { syntax: ImplFor, position: None, generic_count: 0, at_count: 1, gat_count: Some(0), trait_name: "Iterator", trait_bounds_count: 0, lifetime_bounds_count: 0 }"#,
-    ));
+    let db = RUNNER.collect_mock("impl_for");
+    assert!(db.contains_row(&Row {
+        syntax: SyntaxType::ImplFor,
+        position: None,
+        generic_count: 0,
+        at_count: 1,
+        gat_count: Some(0),
+        trait_name: "Iterator".to_string(),
+        trait_bounds_count: 0,
+        lifetime_bounds_count: 0,
+        line_number: 3,
+        depth: None,
+        written_path: Some("Iterator".to_string()),
+        canonical_path: Some("std::iter::Iterator".to_string()),
+        origin: Some(PathOrigin::Std),
+    }));
 }
 
 #[test]
-#[traced_test]
 fn test_iterator_arg() {
-    RUNNER.collect_mock("iterator_arg");
-    assert!(logs_contain(
-        r#"row=Row { syntax: TypeImpl, position: Some(Argument), generic_count: 0, at_count: 1, gat_count: None, trait_name: "Iterator", trait_bounds_count: 1, lifetime_bounds_count: 0 }"#,
-    ));
+    let db = RUNNER.collect_mock("iterator_arg");
+    assert!(db.contains_row(&Row {
+        syntax: SyntaxType::TypeImpl,
+        position: Some(PositionType::Argument),
+        generic_count: 0,
+        at_count: 1,
+        gat_count: None,
+        trait_name: "Iterator".to_string(),
+        trait_bounds_count: 1,
+        lifetime_bounds_count: 0,
+        line_number: 1,
+        depth: Some(0),
+        written_path: Some("Iterator".to_string()),
+        canonical_path: Some("std::iter::Iterator".to_string()),
+        origin: Some(PathOrigin::Std),
+    }));
 }
 
 #[test]
-#[traced_test]
 fn test_iterator_ret() {
-    RUNNER.collect_mock("iterator_ret");
-    assert!(logs_contain(
-        r#"row=Row { syntax: TypeImpl, position: Some(Return), generic_count: 0, at_count: 1, gat_count: None, trait_name: "Iterator", trait_bounds_count: 1, lifetime_bounds_count: 0 }"#,
-    ));
+    let db = RUNNER.collect_mock("iterator_ret");
+    assert!(db.contains_row(&Row {
+        syntax: SyntaxType::TypeImpl,
+        position: Some(PositionType::Return),
+        generic_count: 0,
+        at_count: 1,
+        gat_count: None,
+        trait_name: "Iterator".to_string(),
+        trait_bounds_count: 1,
+        lifetime_bounds_count: 0,
+        line_number: 1,
+        depth: Some(0),
+        written_path: Some("Iterator".to_string()),
+        canonical_path: Some("std::iter::Iterator".to_string()),
+        origin: Some(PathOrigin::Std),
+    }));
 }
 
 #[test]
-#[traced_test]
 fn test_iterator_ret_lifetime_bounds() {
-    RUNNER.collect_mock("iterator_ret");
-    assert!(logs_contain(
-        r#"row=Row { syntax: TypeImpl, position: Some(Return), generic_count: 0, at_count: 1, gat_count: None, trait_name: "Iterator", trait_bounds_count: 1, lifetime_bounds_count: 2 }"#,
-    ));
+    let db = RUNNER.collect_mock("iterator_ret");
+    assert!(db.contains_row(&Row {
+        syntax: SyntaxType::TypeImpl,
+        position: Some(PositionType::Return),
+        generic_count: 0,
+        at_count: 1,
+        gat_count: None,
+        trait_name: "Iterator".to_string(),
+        trait_bounds_count: 1,
+        lifetime_bounds_count: 2,
+        line_number: 5,
+        depth: Some(0),
+        written_path: Some("Iterator".to_string()),
+        canonical_path: Some("std::iter::Iterator".to_string()),
+        origin: Some(PathOrigin::Std),
+    }));
 }
 
 #[test]
-#[traced_test]
 fn test_dyn_iterator_arg() {
-    RUNNER.collect_mock("dyn_iterator_arg");
-    assert!(logs_contain(
-        r#"row=Row { syntax: TypeDyn, position: Some(Argument), generic_count: 0, at_count: 1, gat_count: None, trait_name: "Iterator", trait_bounds_count: 1, lifetime_bounds_count: 0 }"#,
-    ));
+    let db = RUNNER.collect_mock("dyn_iterator_arg");
+    assert!(db.contains_row(&Row {
+        syntax: SyntaxType::TypeDyn,
+        position: Some(PositionType::Argument),
+        generic_count: 0,
+        at_count: 1,
+        gat_count: None,
+        trait_name: "Iterator".to_string(),
+        trait_bounds_count: 1,
+        lifetime_bounds_count: 0,
+        line_number: 1,
+        depth: Some(0),
+        written_path: Some("Iterator".to_string()),
+        canonical_path: Some("std::iter::Iterator".to_string()),
+        origin: Some(PathOrigin::Std),
+    }));
 }
 
 #[test]
-#[traced_test]
 fn test_many_generics() {
-    RUNNER.collect_mock("many_generics");
-    assert!(logs_contain(
-        r#"row=Row { syntax: TraitDef, position: None, generic_count: 3, at_count: 1, gat_count: Some(0), trait_name: "Mock", trait_bounds_count: 0, lifetime_bounds_count: 0 }"#,
+    let db = RUNNER.collect_mock("many_generics");
+    assert!(db.contains_row(&Row {
+        syntax: SyntaxType::TraitDef,
+        position: None,
+        generic_count: 3,
+        at_count: 1,
+        gat_count: Some(0),
+        trait_name: "Mock".to_string(),
+        trait_bounds_count: 0,
+        lifetime_bounds_count: 0,
+        line_number: 1,
+        depth: None,
+        written_path: Some("Mock".to_string()),
+        canonical_path: Some("crate::Mock".to_string()),
+        origin: Some(PathOrigin::Local),
+    }));
+    assert!(db.contains_row(&Row {
+        syntax: SyntaxType::TypeImpl,
+        position: Some(PositionType::Argument),
+        generic_count: 3,
+        at_count: 0,
+        gat_count: None,
+        trait_name: "Mock".to_string(),
+        trait_bounds_count: 1,
+        lifetime_bounds_count: 0,
+        line_number: 5,
+        depth: Some(0),
+        written_path: Some("Mock".to_string()),
+        canonical_path: Some("crate::Mock".to_string()),
+        origin: Some(PathOrigin::Local),
+    }));
+}
+
+#[test]
+fn test_define_gat() {
+    let db = RUNNER.collect_mock("define_gat");
+    assert!(db.contains_row(&Row {
+        syntax: SyntaxType::TraitDef,
+        position: None,
+        generic_count: 0,
+        at_count: 1,
+        gat_count: Some(1),
+        trait_name: "LendingIterator".to_string(),
+        trait_bounds_count: 0,
+        lifetime_bounds_count: 0,
+        line_number: 3,
+        depth: None,
+        written_path: Some("LendingIterator".to_string()),
+        canonical_path: Some("crate::LendingIterator".to_string()),
+        origin: Some(PathOrigin::Local),
+    }));
+}
+
+#[test]
+fn test_where_clause() {
+    let db = RUNNER.collect_mock("where_clause");
+    assert!(db.contains_row(&Row {
+        syntax: SyntaxType::WhereClause,
+        position: None,
+        generic_count: 0,
+        at_count: 1,
+        gat_count: None,
+        trait_name: "Iterator".to_string(),
+        trait_bounds_count: 1,
+        lifetime_bounds_count: 0,
+        line_number: 3,
+        depth: None,
+        written_path: Some("Iterator".to_string()),
+        canonical_path: Some("std::iter::Iterator".to_string()),
+        origin: Some(PathOrigin::Std),
+    }));
+}
+
+#[test]
+fn test_generic_param() {
+    let db = RUNNER.collect_mock("generic_param");
+    assert!(db.contains_row(&Row {
+        syntax: SyntaxType::GenericParam,
+        position: None,
+        generic_count: 0,
+        at_count: 1,
+        gat_count: None,
+        trait_name: "Iterator".to_string(),
+        trait_bounds_count: 2,
+        lifetime_bounds_count: 1,
+        line_number: 1,
+        depth: None,
+        written_path: Some("Iterator".to_string()),
+        canonical_path: Some("std::iter::Iterator".to_string()),
+        origin: Some(PathOrigin::Std),
+    }));
+    assert!(db.contains_row(&Row {
+        syntax: SyntaxType::AssocTypeBound,
+        position: None,
+        generic_count: 0,
+        at_count: 0,
+        gat_count: None,
+        trait_name: "Clone".to_string(),
+        trait_bounds_count: 1,
+        lifetime_bounds_count: 0,
+        line_number: 6,
+        depth: None,
+        written_path: Some("Clone".to_string()),
+        canonical_path: Some("std::clone::Clone".to_string()),
+        origin: Some(PathOrigin::Std),
+    }));
+
+    // Generics of impls, methods and traits, and nested associated types
+    let bound = |syntax, trait_name: &str, line_number| {
+        db.contains(
+            Row::TABLE,
+            &[
+                ("syntax", &syntax),
+                ("trait_name", &trait_name),
+                ("line_number", &line_number),
+            ],
+        )
+    };
+    assert!(bound(SyntaxType::GenericParam, "Default", 11));
+    assert!(bound(SyntaxType::WhereClause, "Sync", 13));
+    assert!(bound(SyntaxType::GenericParam, "From", 15));
+    assert!(bound(SyntaxType::GenericParam, "Copy", 20));
+    assert!(bound(SyntaxType::AssocTypeBound, "Iterator", 21));
+    assert!(bound(SyntaxType::AssocTypeBound, "Ord", 21));
+    assert!(bound(SyntaxType::GenericParam, "Read", 24));
+}
+
+#[test]
+fn test_positions() {
+    let db = RUNNER.collect_mock("positions");
+    let dyn_row = |canonical_path: &str, position, depth, line_number| Row {
+        syntax: SyntaxType::TypeDyn,
+        position: Some(position),
+        generic_count: 0,
+        at_count: 0,
+        gat_count: None,
+        trait_name: canonical_path.rsplit("::").next().unwrap().to_string(),
+        trait_bounds_count: 1,
+        lifetime_bounds_count: 0,
+        line_number,
+        depth: Some(depth),
+        written_path: Some(canonical_path.rsplit("::").next().unwrap().to_string()),
+        canonical_path: Some(canonical_path.to_string()),
+        origin: Some(PathOrigin::Std),
+    };
+    assert!(db.contains_row(&dyn_row("std::ops::Fn", PositionType::Field, 2, 2)));
+    assert!(db.contains_row(&dyn_row("std::fmt::Debug", PositionType::Field, 4, 3)));
+    assert!(db.contains_row(&dyn_row("std::fmt::Debug", PositionType::TypeAlias, 1, 6)));
+    assert!(db.contains_row(&dyn_row("std::marker::Sync", PositionType::Static, 2, 7)));
+    assert!(db.contains_row(&dyn_row("std::fmt::Debug", PositionType::ImplSelf, 0, 9)));
+    assert!(db.contains_row(&dyn_row("std::fmt::Debug", PositionType::Let, 1, 12)));
+    assert!(db.contains_row(&dyn_row("std::fmt::Debug", PositionType::Cast, 1, 13)));
+
+    // Inside of impls
+    assert!(db.contains_row(&dyn_row("std::marker::Sync", PositionType::Const, 1, 21)));
+    assert!(db.contains_row(&dyn_row("std::fmt::Debug", PositionType::Argument, 1, 23)));
+    assert!(db.contains_row(&dyn_row("std::fmt::Debug", PositionType::Return, 1, 23)));
+    assert!(db.contains_row(&dyn_row("std::fmt::Debug", PositionType::Let, 1, 24)));
+    assert!(db.contains_row(&dyn_row("std::fmt::Debug", PositionType::Cast, 1, 25)));
+    assert!(db.contains(
+        Row::TABLE,
+        &[
+            ("syntax", &SyntaxType::TypeImpl),
+            ("position", &PositionType::Argument),
+            ("trait_name", &"Fn"),
+            ("line_number", &23),
+        ],
     ));
-    assert!(logs_contain(
-        r#"row=Row { syntax: TypeImpl, position: Some(Argument), generic_count: 3, at_count: 0, gat_count: None, trait_name: "Mock", trait_bounds_count: 1, lifetime_bounds_count: 0 }"#,
+}
+
+#[test]
+fn test_resolved_paths() {
+    let db = RUNNER.collect_mock("resolved_paths");
+    let resolved = |written_path: &str, canonical_path: &str, origin| {
+        db.contains(
+            Row::TABLE,
+            &[
+                ("written_path", &written_path),
+                ("canonical_path", &canonical_path),
+                ("origin", &origin),
+            ],
+        )
+    };
+    assert!(resolved("io::Read", "std::io::Read", PathOrigin::Std));
+    assert!(resolved("IoWrite", "std::io::Write", PathOrigin::Std));
+    assert!(resolved("ops::Add", "crate::ops::Add", PathOrigin::Local));
+    assert!(resolved(
+        "super::ops::Add",
+        "crate::ops::Add",
+        PathOrigin::Local
+    ));
+    assert!(resolved("std::ops::Add", "std::ops::Add", PathOrigin::Std));
+    assert!(resolved(
+        "Stream",
+        "futures::stream::Stream",
+        PathOrigin::External
     ));
+    assert!(resolved("Add", "crate::ops::Add", PathOrigin::Local));
 }
 
 #[test]
-#[traced_test]
-fn test_define_gat() {
-    RUNNER.collect_mock("define_gat");
-    assert!(logs_contain(
-        r#"row=Row { syntax: TraitDef, position: None, generic_count: 0, at_count: 1, gat_count: Some(1), trait_name: "LendingIterator", trait_bounds_count: 0, lifetime_bounds_count: 0 }"#,
+fn test_bindings() {
+    let db = RUNNER.collect_mock("bindings");
+    let binding = |assoc_type: &str, bound_type: &str, bound_kind| {
+        db.contains(
+            Binding::TABLE,
+            &[
+                ("assoc_type", &assoc_type),
+                ("bound_type", &bound_type),
+                ("bound_kind", &bound_kind),
+            ],
+        )
+    };
+    assert!(binding("Item", "& 'static str", BoundKind::Other));
+    assert!(binding("Item", "T", BoundKind::Generic));
+    assert!(binding(
+        "Output",
+        "Box < dyn Iterator < Item = u8 > >",
+        BoundKind::Other
+    ));
+    assert!(binding("Item", "u8", BoundKind::Other));
+    assert!(binding("Item", "impl Clone", BoundKind::Impl));
+    assert!(binding("Item", "Box < dyn Send >", BoundKind::Other));
+
+    // Bounds of methods
+    assert!(binding("Item", "u16", BoundKind::Other));
+
+    let source = std::fs::read_to_string("mocks/bindings.rs").unwrap();
+    let trait_start_byte = source.find("IntoIterator").unwrap();
+    assert!(db.contains(
+        Binding::TABLE,
+        &[
+            ("trait_start_byte", &trait_start_byte),
+            ("bound_type", &"T")
+        ],
     ));
+
+    // Every binding refers to exactly one trait
+    for row in db.rows(Binding::TABLE) {
+        let (_, start_byte) = row.iter().find(|(c, _)| c == "trait_start_byte").unwrap();
+        let traits = db
+            .rows(Row::TABLE)
+            .iter()
+            .filter(|r| r.iter().any(|(c, v)| c == "start_byte" && v == start_byte))
+            .count();
+        assert_eq!(traits, 1);
+    }
 }
 
 #[test]
-#[traced_test]
-fn test_where_clause() {
-    RUNNER.collect_mock("where_clause");
-    assert!(logs_contain(
-        r#"row=Row { syntax: WhereClause, position: None, generic_count: 0, at_count: 1, gat_count: None, trait_name: "Iterator", trait_bounds_count: 1, lifetime_bounds_count: 0 }"#,
+fn test_glob_import() {
+    let db = RUNNER.collect_mock("glob_import");
+    assert!(db.contains(
+        Row::TABLE,
+        &[
+            ("written_path", &"Foo"),
+            ("canonical_path", &"lib::Foo"),
+            ("origin", &PathOrigin::External),
+        ],
     ));
 }
@@ -1,12 +1,12 @@
-use crate::sql_enum;
+use crate::db::{add_columns, add_location_columns, create_table, SqlRow, Table};
+use crate::roles::add_role_column;
+use crate::stats::{self, FnItem, FnItemKind, FnItemVisitor};
+use crate::{sql_enum, sql_row};
 use quote::ToTokens;
 use syn::{
     spanned::Spanned,
     visit::{self, Visit},
 };
-use tracing::trace;
-#[cfg(test)]
-use tracing_test::traced_test;
 
 sql_enum! {
     enum UnsafeCodeType {
@@ -15,6 +15,28 @@ sql_enum! {
     }
 }
 
+sql_row! {
+    #[table = "unsafe_code"]
+    pub struct Row {
+        unsafe_code_type: UnsafeCodeType,
+        block_count: Option<usize>,
+        first_line_number: usize,
+        last_line_number: usize,
+        outermost: bool,
+        #[since = 4]
+        item_kind: Option<FnItemKind>,
+    }
+}
+
+sql_row! {
+    #[table = "transmutes"]
+    pub struct Transmute {
+        from_type: Option<String>,
+        to_type: Option<String>,
+        line_number: usize,
+    }
+}
+
 #[derive(Default, Debug)]
 struct CallParamList {
     params: Vec<String>,
@@ -22,42 +44,64 @@ struct CallParamList {
 
 impl Visit<'_> for CallParamList {
     fn visit_generic_argument(&mut self, node: &syn::GenericArgument) {
-        match node {
-            syn::GenericArgument::Type(t) => self.params.push(t.to_token_stream().to_string()),
-            _ => {}
+        if let syn::GenericArgument::Type(t) = node {
+            self.params.push(t.to_token_stream().to_string())
         }
     }
 }
 
-impl Visit<'_> for Stats<'_, '_> {
-    fn visit_item_fn(&mut self, node: &syn::ItemFn) {
+impl<'ast> FnItemVisitor<'ast> for Stats<'_, '_> {
+    fn in_trait_impl(&mut self) -> &mut bool {
+        &mut self.in_trait_impl
+    }
+
+    /// Count the unsafe blocks of a function, and record it if it is an
+    /// `unsafe fn`.
+    fn visit_fn(&mut self, item: FnItem<'ast>, item_kind: FnItemKind) {
         let mut child = Stats {
             log: self.log.fork(),
             count: 0,
             outermost: false,
+            in_trait_impl: false,
         };
-        visit::visit_item_fn(&mut child, node);
+        item.visit(&mut child);
 
-        if node.sig.unsafety.is_none() {
+        if item.sig().unsafety.is_none() {
             return;
         }
 
         let count = child.count;
+        let span = item.span();
 
-        self.log.db.execute(
-            r"INSERT INTO unsafe_code (unsafe_code_type, block_count, file_name, first_line_number, last_line_number, outermost, version_id)
-            VALUES                    ($1,               $2,          $3,        $4,                $5,               $6,        $7)",
-            &[
-                &UnsafeCodeType::Function,
-                &(count as i32),
-                &self.log.file_name,
-                &(node.span().start().line as i32),
-                &(node.span().end().line as i32),
-                &self.outermost,
-                &self.log.version_id],
-        ).unwrap();
-
-        trace!(count = count, ty = "Function", outermost = self.outermost);
+        self.log.push_at(
+            &Row {
+                unsafe_code_type: UnsafeCodeType::Function,
+                block_count: Some(count),
+                first_line_number: span.start().line,
+                last_line_number: span.end().line,
+                outermost: self.outermost,
+                item_kind: Some(item_kind),
+            },
+            span,
+        );
+    }
+}
+
+impl<'ast> Visit<'ast> for Stats<'_, '_> {
+    fn visit_item_fn(&mut self, node: &'ast syn::ItemFn) {
+        stats::visit_item_fn(self, node);
+    }
+
+    fn visit_item_impl(&mut self, node: &'ast syn::ItemImpl) {
+        stats::visit_item_impl(self, node);
+    }
+
+    fn visit_impl_item_method(&mut self, node: &'ast syn::ImplItemMethod) {
+        stats::visit_impl_item_method(self, node);
+    }
+
+    fn visit_trait_item_method(&mut self, node: &'ast syn::TraitItemMethod) {
+        stats::visit_trait_item_method(self, node);
     }
 
     fn visit_expr_unsafe(&mut self, node: &syn::ExprUnsafe) {
@@ -65,25 +109,23 @@ impl Visit<'_> for Stats<'_, '_> {
             log: self.log.fork(),
             count: 0,
             outermost: false,
+            in_trait_impl: false,
         };
         visit::visit_expr_unsafe(&mut child, node);
         self.count += child.count;
         self.count += 1;
 
-        self.log.db.execute(
-            r"INSERT INTO unsafe_code (unsafe_code_type, block_count, file_name, first_line_number, last_line_number, outermost, version_id)
-            VALUES                    ($1,               $2,          $3,        $4,                $5,               $6,        $7)",
-            &[
-                &UnsafeCodeType::Block,
-                &None::<i32>,
-                &self.log.file_name,
-                &(node.span().start().line as i32),
-                &(node.span().end().line as i32),
-                &self.outermost,
-                &self.log.version_id],
-        ).unwrap();
-
-        trace!(ty = "Block", outermost = self.outermost);
+        self.log.push_at(
+            &Row {
+                unsafe_code_type: UnsafeCodeType::Block,
+                block_count: None,
+                first_line_number: node.span().start().line,
+                last_line_number: node.span().end().line,
+                outermost: self.outermost,
+                item_kind: None,
+            },
+            node.span(),
+        );
     }
 
     fn visit_expr_call(&mut self, node: &syn::ExprCall) {
@@ -91,6 +133,7 @@ impl Visit<'_> for Stats<'_, '_> {
             log: self.log.fork(),
             count: 0,
             outermost: false,
+            in_trait_impl: false,
         };
         visit::visit_expr_call(&mut child, node);
         self.count += child.count;
@@ -113,15 +156,14 @@ impl Visit<'_> for Stats<'_, '_> {
             return;
         }
 
-        let from_type = list.params.get(0);
-        let to_type = list.params.get(1);
-
-        self.log.db.execute(
-            r"INSERT INTO transmutes (from_type, to_type, file_name, line_number, version_id) VALUES ($1, $2, $3, $4, $5)",
-            &[&from_type, &to_type, &self.log.file_name, &(node.span().start().line as i32), &self.log.version_id],
-        ).unwrap();
-
-        trace!(transmute = true, from = from_type, to = to_type);
+        self.log.push_at(
+            &Transmute {
+                from_type: list.params.first().cloned(),
+                to_type: list.params.get(1).cloned(),
+                line_number: node.span().start().line,
+            },
+            node.span(),
+        );
     }
 }
 
@@ -129,73 +171,119 @@ struct Stats<'log, 'db> {
     log: super::Logger<'log, 'db>,
     count: usize,
     outermost: bool,
+    in_trait_impl: bool,
 }
 
 pub const RUNNER: super::Runner = super::Runner {
+    name: "unsafe_code",
+    version: 2,
+    tables: &[Table::located::<Row>(), Table::located::<Transmute>()],
     collect: |file, log| {
         visit::visit_file(
             &mut Stats {
                 log,
                 count: 0,
                 outermost: true,
+                in_trait_impl: false,
             },
             file,
         )
     },
-    init: |db| {
-        UnsafeCodeType::init(db);
-        db.batch_execute(
-            r#"
-            CREATE TABLE unsafe_code (
-                unsafe_code_type "UnsafeCodeType",
-                block_count INT,
-                file_name TEXT,
-                first_line_number INT,
-                last_line_number INT,
-                outermost BOOL,
-                version_id UUID references versions(id)
-            );
-            CREATE INDEX usafe_code_version_index ON unsafe_code(version_id);
-            CREATE TABLE transmutes (
-                from_type TEXT,
-                to_type TEXT,
-                file_name TEXT,
-                line_number INT,
-                version_id UUID references versions(id)
-            );
-            CREATE INDEX transmutes_version_index ON transmutes(version_id);
-        "#,
-        )
-        .unwrap();
-    },
+    migrations: &[
+        |db| {
+            UnsafeCodeType::init(db)?;
+            create_table::<Row>(db)?;
+            create_table::<Transmute>(db)
+        },
+        |db| {
+            add_location_columns(db, Row::TABLE)?;
+            add_location_columns(db, Transmute::TABLE)
+        },
+        |db| {
+            add_role_column(db, Row::TABLE)?;
+            add_role_column(db, Transmute::TABLE)
+        },
+        |db| {
+            FnItemKind::init(db)?;
+            add_columns::<Row>(db, 4)
+        },
+    ],
 };
 
 #[test]
-#[traced_test]
 fn test_unsafe_fn() {
-    RUNNER.collect_mock("unsafe_fn");
-    assert!(logs_contain(r#"ty="Block" outermost=false"#));
-    assert!(logs_contain(r#"count=4 ty="Function" outermost=true"#));
+    let db = RUNNER.collect_mock("unsafe_fn");
+    assert!(db.contains(
+        "unsafe_code",
+        &[
+            ("unsafe_code_type", &UnsafeCodeType::Block),
+            ("outermost", &false),
+        ],
+    ));
+    assert!(db.contains(
+        "unsafe_code",
+        &[
+            ("unsafe_code_type", &UnsafeCodeType::Function),
+            ("block_count", &4),
+            ("outermost", &true),
+            ("item_kind", &FnItemKind::FreeFn),
+        ],
+    ));
+}
+
+#[test]
+fn test_unsafe_methods() {
+    let db = RUNNER.collect_mock("unsafe_methods");
+    let function = |item_kind, block_count| {
+        db.contains(
+            "unsafe_code",
+            &[
+                ("unsafe_code_type", &UnsafeCodeType::Function),
+                ("item_kind", &item_kind),
+                ("block_count", &block_count),
+            ],
+        )
+    };
+    assert!(function(FnItemKind::InherentMethod, 1));
+    assert!(function(FnItemKind::TraitMethod, 0));
+    assert!(function(FnItemKind::TraitMethod, 2));
+    assert!(function(FnItemKind::TraitImplMethod, 0));
+    assert!(!db.contains("unsafe_code", &[("item_kind", &FnItemKind::FreeFn)]));
 }
 
 #[test]
-#[traced_test]
 fn test_safe_fn() {
-    RUNNER.collect_mock("iterator_arg");
-    assert!(!logs_contain(r#"ty="Block""#));
-    assert!(!logs_contain(r#"count=0 ty="Function""#));
+    let db = RUNNER.collect_mock("iterator_arg");
+    assert!(db.rows("unsafe_code").is_empty());
 }
 
 #[test]
-#[traced_test]
 fn test_transmute_with_arguments() {
-    RUNNER.collect_mock("transmute_with_arguments");
-    assert!(logs_contain(r#"transmute=true from="[u8 ; 4]" to="u32""#));
+    let db = RUNNER.collect_mock("transmute_with_arguments");
+    assert!(db.contains_row(&Transmute {
+        from_type: Some("[u8 ; 4]".to_string()),
+        to_type: Some("u32".to_string()),
+        line_number: 2,
+    }));
+    assert!(db.contains(
+        "transmutes",
+        &[
+            ("start_line", &2),
+            ("start_column", &12),
+            ("end_column", &58),
+            ("start_byte", &30),
+            ("end_byte", &76),
+            ("item_path", &"foo"),
+        ],
+    ));
 }
 
 #[test]
-#[traced_test]
 fn test_transmute_without_arguments() {
-    RUNNER.collect_mock("transmute_without_arguments");
-    assert!(logs_contain(r#"transmute=true"#));
+    let db = RUNNER.collect_mock("transmute_without_arguments");
+    assert!(db.contains_row(&Transmute {
+        from_type: None,
+        to_type: None,
+        line_number: 2,
+    }));
 }
@@ -1,9 +1,10 @@
 #[macro_export]
 macro_rules! sql_enum {
-	{ $vis:vis enum $name:ident { $($var:ident),* $(,)* } } => {
-		#[derive(Debug, Clone, Copy, PartialEq, Eq, ::postgres_types::ToSql)]
+	{ $(#[$meta:meta])* $vis:vis enum $name:ident { $($(#[$var_meta:meta])* $var:ident),* $(,)* } } => {
+		$(#[$meta])*
+		#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 		$vis enum $name {
-			$($var),*
+			$($(#[$var_meta])* $var),*
 		}
 
 		impl AsRef<str> for $name {
@@ -15,19 +16,72 @@ macro_rules! sql_enum {
 		}
 
 		impl $name {
-			pub fn init(tx: &mut ::postgres::Transaction) {
-				let query = format!(
-					"CREATE TYPE {:?} as ENUM ({});",
-					stringify!($name),
-					[$(concat!("'", stringify!($var), "'")),*].join(","),
-				);
-				tx.batch_execute(&query).unwrap();
+			pub fn init(tx: &mut dyn $crate::db::Database) -> ::anyhow::Result<()> {
+				tx.create_enum(stringify!($name), &[$(stringify!($var)),*])
 			}
 		}
 
-		impl ::rusqlite::types::ToSql for $name {
-			fn to_sql(&self) -> ::rusqlite::Result<::rusqlite::types::ToSqlOutput<'_>> {
-				<str as ::rusqlite::types::ToSql>::to_sql(self.as_ref())
+		impl $crate::db::SqlType for $name {
+			const SQL_TYPE: &'static str = concat!("\"", stringify!($name), "\"");
+		}
+
+		impl $crate::db::ToValue for $name {
+			fn to_value(&self) -> $crate::db::Value {
+				match self {
+					$(Self::$var => $crate::db::Value::Enum(stringify!($var))),*
+				}
+			}
+		}
+	}
+}
+
+/// Declares a row struct for a runner's table, see [`crate::db::SqlRow`].
+///
+/// Columns added after the table was created must be marked with
+/// `#[since = N]`, where `N` is the migration which adds them, so that
+/// [`add_columns`](crate::db::add_columns) can add them. Primary keys are
+/// marked with `#[primary_key]` and foreign keys with
+/// `#[references = "table(column)"]` after that.
+#[macro_export]
+macro_rules! sql_row {
+	(@since) => { None };
+	(@since $since:literal) => { Some($since) };
+	(@references) => { None };
+	(@references $target:literal) => { Some($target) };
+	(@primary_key) => { false };
+	(@primary_key $($marked:tt)+) => { true };
+	{
+		#[table = $table:literal]
+		$(#[$meta:meta])*
+		$vis:vis struct $name:ident {
+			$(
+				$(#[since = $since:literal])?
+				$(#[primary_key $($primary_key:tt)*])?
+				$(#[references = $target:literal])?
+				$fvis:vis $field:ident : $ty:ty
+			),* $(,)*
+		}
+	} => {
+		$(#[$meta])*
+		#[derive(Debug, Clone, PartialEq, Eq)]
+		$vis struct $name {
+			$($fvis $field: $ty),*
+		}
+
+		impl $crate::db::SqlRow for $name {
+			const TABLE: &'static str = $table;
+			const COLUMNS: &'static [$crate::db::Column] = &[$(
+				$crate::db::Column {
+					name: stringify!($field),
+					sql_type: <$ty as $crate::db::SqlType>::SQL_TYPE,
+					since: $crate::sql_row!(@since $($since)?),
+					primary_key: $crate::sql_row!(@primary_key $($($primary_key)* marked)?),
+					references: $crate::sql_row!(@references $($target)?),
+				}
+			),*];
+
+			fn values(&self) -> Vec<&dyn $crate::db::ToValue> {
+				vec![$(&self.$field),*]
 			}
 		}
 	}
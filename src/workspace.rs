@@ -0,0 +1,741 @@
+use crate::db::{Database, SqlRow};
+use crate::{sql_enum, sql_row};
+use anyhow::Error;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Component, Path, PathBuf};
+use uuid::Uuid;
+
+sql_enum! {
+    /// The kind of Cargo target a file is compiled into.
+    pub enum TargetKind {
+        Lib,
+        ProcMacro,
+        Bin,
+        Test,
+        Bench,
+        Example,
+        BuildScript,
+    }
+}
+
+sql_enum! {
+    pub enum DependencyKind {
+        Normal,
+        Dev,
+        Build,
+    }
+}
+
+sql_enum! {
+    pub enum DependencySource {
+        Registry,
+        Path,
+        Git,
+    }
+}
+
+sql_row! {
+    #[table = "files"]
+    /// A Rust file of a version which was analyzed, with the package and target
+    /// it belongs to. Runner rows join on `version_id` and `file_name`.
+    pub struct SourceFile {
+        pub package: Option<String>,
+        pub target_kind: Option<TargetKind>,
+        pub target_name: Option<String>,
+        // Lines as counted by tokei, unless the file could not be read
+        #[since = 9]
+        pub code: Option<usize>,
+        #[since = 9]
+        pub comments: Option<usize>,
+        #[since = 9]
+        pub blanks: Option<usize>,
+    }
+}
+
+sql_row! {
+    #[table = "manifests"]
+    /// The manifest of a package, with `file_name` the path of its `Cargo.toml`.
+    pub struct PackageManifest {
+        pub package: String,
+        // Cargo's default of 2015 if the manifest has none
+        pub edition: String,
+        pub rust_version: Option<String>,
+        pub proc_macro: bool,
+    }
+}
+
+sql_row! {
+    #[table = "manifest_features"]
+    pub struct ManifestFeature {
+        pub package: String,
+        pub feature: String,
+        // Comma separated features and dependencies which it enables
+        pub enables: String,
+    }
+}
+
+sql_row! {
+    #[table = "manifest_dependencies"]
+    pub struct ManifestDependency {
+        pub package: String,
+        // The package depended on, rather than the name it is renamed to
+        pub dependency: String,
+        pub kind: DependencyKind,
+        pub version_req: Option<String>,
+        pub source: DependencySource,
+        pub optional: bool,
+        // The key of a `[target.<platform>.dependencies]` table
+        pub platform: Option<String>,
+    }
+}
+
+/// The parts of a `Cargo.toml` which are recorded or decide the targets of its
+/// package.
+#[derive(Deserialize)]
+struct Manifest {
+    package: Option<PackageTable>,
+    workspace: Option<WorkspaceTable>,
+    lib: Option<TargetTable>,
+    #[serde(default)]
+    bin: Vec<TargetTable>,
+    #[serde(default)]
+    test: Vec<TargetTable>,
+    #[serde(default)]
+    bench: Vec<TargetTable>,
+    #[serde(default)]
+    example: Vec<TargetTable>,
+    #[serde(default)]
+    features: BTreeMap<String, Vec<String>>,
+    #[serde(flatten)]
+    dependencies: DependencyTables,
+    #[serde(default)]
+    target: BTreeMap<String, DependencyTables>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct PackageTable {
+    name: String,
+    edition: Option<Inheritable<String>>,
+    rust_version: Option<Inheritable<String>>,
+    // A path, or `false` to disable the `build.rs` default
+    build: Option<toml::Value>,
+    autobins: Option<bool>,
+    autotests: Option<bool>,
+    autobenches: Option<bool>,
+    autoexamples: Option<bool>,
+}
+
+/// A `[package]` field, which may be taken from `[workspace.package]` instead.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Inheritable<T> {
+    Value(T),
+    Workspace {
+        #[allow(unused)]
+        workspace: bool,
+    },
+}
+
+#[derive(Deserialize, Default)]
+struct WorkspaceTable {
+    #[serde(default)]
+    package: WorkspacePackage,
+    #[serde(default)]
+    dependencies: BTreeMap<String, toml::Value>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+struct WorkspacePackage {
+    edition: Option<String>,
+    rust_version: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+struct DependencyTables {
+    #[serde(default)]
+    dependencies: BTreeMap<String, toml::Value>,
+    #[serde(default, alias = "dev_dependencies")]
+    dev_dependencies: BTreeMap<String, toml::Value>,
+    #[serde(default, alias = "build_dependencies")]
+    build_dependencies: BTreeMap<String, toml::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct TargetTable {
+    name: Option<String>,
+    path: Option<String>,
+    #[serde(default, alias = "proc_macro")]
+    proc_macro: bool,
+}
+
+/// A target of a package, with its root file relative to the repository.
+struct Target {
+    kind: TargetKind,
+    name: String,
+    root: PathBuf,
+}
+
+struct Package {
+    name: String,
+    dir: PathBuf,
+    targets: Vec<Target>,
+    manifest_name: String,
+    manifest: PackageManifest,
+    features: Vec<ManifestFeature>,
+    dependencies: Vec<ManifestDependency>,
+}
+
+/// The packages of a snapshot. Every `Cargo.toml` with a `[package]` table is
+/// read, so workspace members are found wherever the workspace puts them, as
+/// are packages it excludes.
+#[derive(Default)]
+pub struct Workspace {
+    packages: Vec<Package>,
+}
+
+impl Workspace {
+    /// Read the packages from the `manifests` of a snapshot. `files` are the
+    /// paths of all of its Rust files, which Cargo's target auto-discovery
+    /// depends on.
+    pub fn new<'a>(
+        manifests: &[(String, String)],
+        files: impl IntoIterator<Item = &'a str>,
+    ) -> Self {
+        let files: BTreeSet<&str> = files.into_iter().collect();
+        let mut parsed = Vec::new();
+        for (manifest_name, contents) in manifests {
+            match toml::from_str::<Manifest>(contents) {
+                Ok(manifest) => parsed.push((manifest_name, manifest)),
+                Err(err) => eprintln!("Error parsing {manifest_name}: {err}"),
+            }
+        }
+        let dir = |manifest_name: &str| {
+            Path::new(manifest_name)
+                .parent()
+                .unwrap_or(Path::new(""))
+                .to_owned()
+        };
+        let roots: Vec<_> = parsed
+            .iter()
+            .filter_map(|(name, m)| Some((dir(name), m.workspace.as_ref()?)))
+            .collect();
+
+        let mut workspace = Workspace::default();
+        for (manifest_name, manifest) in &parsed {
+            let dir = dir(manifest_name);
+            // Members are below their workspace root, usually right below
+            let root = roots
+                .iter()
+                .filter(|(root, _)| dir.starts_with(root))
+                .max_by_key(|(root, _)| root.components().count())
+                .map(|(_, table)| *table);
+            if let Some(package) = Package::read(manifest, manifest_name, dir, root, &files) {
+                workspace.packages.push(package);
+            }
+        }
+        workspace
+    }
+
+    /// The package and target `file_name` belongs to. A file which is not the
+    /// root of a target belongs to the target whose root is in the closest
+    /// directory above it, preferring the library on a tie. Roots right in the
+    /// package directory, like `build.rs`, only claim themselves.
+    pub fn locate(&self, file_name: &str) -> SourceFile {
+        let path = Path::new(file_name);
+        let root_of = self.packages.iter().find_map(|p| {
+            let target = p.targets.iter().find(|t| t.root == path)?;
+            Some((p, target))
+        });
+        let (package, target) = match root_of {
+            Some((package, target)) => (Some(package), Some(target)),
+            None => {
+                let package = self
+                    .packages
+                    .iter()
+                    .filter(|p| path.starts_with(&p.dir))
+                    .max_by_key(|p| p.dir.components().count());
+                let target = package.and_then(|p| {
+                    p.targets
+                        .iter()
+                        // A build script or other root right in the package
+                        // directory would claim every stray file
+                        .filter(|t| t.kind != TargetKind::BuildScript)
+                        .filter(|t| {
+                            t.root
+                                .parent()
+                                .is_some_and(|dir| dir != p.dir && path.starts_with(dir))
+                        })
+                        // `max_by_key` picks the last of equally deep roots
+                        .rev()
+                        .max_by_key(|t| t.root.components().count())
+                });
+                (package, target)
+            }
+        };
+        SourceFile {
+            package: package.map(|p| p.name.clone()),
+            target_kind: target.map(|t| t.kind),
+            target_name: target.map(|t| t.name.clone()),
+            code: None,
+            comments: None,
+            blanks: None,
+        }
+    }
+
+    /// Insert the manifest, features and dependencies of every package.
+    pub fn insert_manifests(&self, db: &mut dyn Database, version_id: Uuid) -> Result<(), Error> {
+        for package in &self.packages {
+            let file_name = &package.manifest_name;
+            insert(db, &package.manifest, file_name, version_id)?;
+            for feature in &package.features {
+                insert(db, feature, file_name, version_id)?;
+            }
+            for dependency in &package.dependencies {
+                insert(db, dependency, file_name, version_id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn insert<R: SqlRow>(
+    db: &mut dyn Database,
+    row: &R,
+    file_name: &str,
+    version_id: Uuid,
+) -> Result<(), Error> {
+    let mut columns = row.columns();
+    columns.push(("file_name", &file_name));
+    columns.push(("version_id", &version_id));
+    db.insert(R::TABLE, &columns)
+}
+
+impl Package {
+    fn read(
+        manifest: &Manifest,
+        manifest_name: &str,
+        dir: PathBuf,
+        workspace: Option<&WorkspaceTable>,
+        files: &BTreeSet<&str>,
+    ) -> Option<Self> {
+        let table = manifest.package.as_ref()?;
+        let lib = manifest.lib.as_ref();
+        let inherited = workspace.map(|w| &w.package);
+        let resolve = |field: &Option<Inheritable<String>>, inherited: Option<&String>| match field
+        {
+            Some(Inheritable::Value(value)) => Some(value.clone()),
+            Some(Inheritable::Workspace { .. }) => inherited.cloned(),
+            None => None,
+        };
+        let mut package = Package {
+            name: table.name.clone(),
+            dir,
+            targets: Vec::new(),
+            manifest_name: manifest_name.to_string(),
+            manifest: PackageManifest {
+                package: table.name.clone(),
+                edition: resolve(&table.edition, inherited.and_then(|p| p.edition.as_ref()))
+                    .unwrap_or_else(|| "2015".to_string()),
+                rust_version: resolve(
+                    &table.rust_version,
+                    inherited.and_then(|p| p.rust_version.as_ref()),
+                ),
+                proc_macro: lib.is_some_and(|l| l.proc_macro),
+            },
+            features: Vec::new(),
+            dependencies: Vec::new(),
+        };
+
+        for (feature, enables) in &manifest.features {
+            package.features.push(ManifestFeature {
+                package: table.name.clone(),
+                feature: feature.clone(),
+                enables: enables.join(", "),
+            });
+        }
+        let platforms = manifest.target.iter().map(|(p, d)| (Some(p), d));
+        for (platform, tables) in std::iter::once((None, &manifest.dependencies)).chain(platforms) {
+            let kinds = [
+                (DependencyKind::Normal, &tables.dependencies),
+                (DependencyKind::Dev, &tables.dev_dependencies),
+                (DependencyKind::Build, &tables.build_dependencies),
+            ];
+            for (kind, dependencies) in kinds {
+                for (name, value) in dependencies {
+                    let inherited = workspace.and_then(|w| w.dependencies.get(name));
+                    let mut dependency = dependency(&table.name, name, value, inherited);
+                    dependency.kind = kind;
+                    dependency.platform = platform.cloned();
+                    package.dependencies.push(dependency);
+                }
+            }
+        }
+
+        let dir = &package.dir;
+        let exists = |rel: &str| files.contains(normalize(&dir.join(rel)).to_str().unwrap());
+        let mut targets = Vec::new();
+        if lib.is_some() || exists("src/lib.rs") {
+            let kind = if package.manifest.proc_macro {
+                TargetKind::ProcMacro
+            } else {
+                TargetKind::Lib
+            };
+            let name = lib
+                .and_then(|l| l.name.clone())
+                .unwrap_or_else(|| table.name.replace('-', "_"));
+            let root = lib.and_then(|l| l.path.clone());
+            targets.push((kind, name, root.unwrap_or_else(|| "src/lib.rs".to_string())));
+        }
+
+        let mut main = Vec::new();
+        if exists("src/main.rs") {
+            main.push((table.name.clone(), "src/main.rs".to_string()));
+        }
+        let kinds = [
+            (TargetKind::Bin, &manifest.bin, table.autobins, "src/bin"),
+            (TargetKind::Test, &manifest.test, table.autotests, "tests"),
+            (
+                TargetKind::Bench,
+                &manifest.bench,
+                table.autobenches,
+                "benches",
+            ),
+            (
+                TargetKind::Example,
+                &manifest.example,
+                table.autoexamples,
+                "examples",
+            ),
+        ];
+        for (kind, declared, auto, target_dir) in kinds {
+            let mut discovered = match kind {
+                TargetKind::Bin => std::mem::take(&mut main),
+                _ => Vec::new(),
+            };
+            discovered.extend(discover_dir(files, dir, target_dir));
+            for target in declared {
+                let Some(name) = &target.name else {
+                    continue;
+                };
+                let root = match &target.path {
+                    Some(path) => path.clone(),
+                    None => discovered
+                        .iter()
+                        .find(|(n, _)| n == name)
+                        .map(|(_, root)| root.clone())
+                        .unwrap_or_else(|| format!("{target_dir}/{name}.rs")),
+                };
+                discovered.retain(|(n, r)| n != name && *r != root);
+                targets.push((kind, name.clone(), root));
+            }
+            if auto != Some(false) {
+                for (name, root) in discovered {
+                    targets.push((kind, name, root));
+                }
+            }
+        }
+
+        let build = match &table.build {
+            Some(toml::Value::String(path)) => Some(path.clone()),
+            Some(toml::Value::Boolean(false)) => None,
+            _ => exists("build.rs").then(|| "build.rs".to_string()),
+        };
+        if let Some(root) = build {
+            targets.push((TargetKind::BuildScript, "build".to_string(), root));
+        }
+
+        for (kind, name, root) in targets {
+            let root = normalize(&package.dir.join(root));
+            package.targets.push(Target { kind, name, root });
+        }
+        Some(package)
+    }
+}
+
+/// Read a dependency of `package`, which is either a version requirement or a
+/// table. With `workspace = true`, missing keys come from the `inherited`
+/// entry of `[workspace.dependencies]`.
+fn dependency(
+    package: &str,
+    name: &str,
+    value: &toml::Value,
+    inherited: Option<&toml::Value>,
+) -> ManifestDependency {
+    let get = |key: &str| {
+        let table = value.as_table()?;
+        if let Some(value) = table.get(key) {
+            return Some(value);
+        }
+        if table.get("workspace")?.as_bool()? {
+            return match inherited? {
+                toml::Value::Table(inherited) => inherited.get(key),
+                version => (key == "version").then_some(version),
+            };
+        }
+        None
+    };
+    let version_req = match value {
+        toml::Value::String(version) => Some(version.clone()),
+        _ => get("version").and_then(|v| v.as_str()).map(str::to_owned),
+    };
+    let source = if get("path").is_some() {
+        DependencySource::Path
+    } else if get("git").is_some() {
+        DependencySource::Git
+    } else {
+        DependencySource::Registry
+    };
+    ManifestDependency {
+        package: package.to_string(),
+        dependency: get("package")
+            .and_then(|p| p.as_str())
+            .unwrap_or(name)
+            .to_string(),
+        kind: DependencyKind::Normal,
+        version_req,
+        source,
+        optional: get("optional").and_then(|o| o.as_bool()).unwrap_or(false),
+        platform: None,
+    }
+}
+
+/// The targets Cargo would discover in `target_dir` of the package in `dir`:
+/// `<name>.rs` and `<name>/main.rs`, with paths relative to the package.
+fn discover_dir(files: &BTreeSet<&str>, dir: &Path, target_dir: &str) -> Vec<(String, String)> {
+    let mut targets = Vec::new();
+    for file_name in files {
+        let Ok(rel) = Path::new(file_name).strip_prefix(dir.join(target_dir)) else {
+            continue;
+        };
+        let parts: Vec<_> = rel.iter().filter_map(|p| p.to_str()).collect();
+        let name = match parts[..] {
+            [file] => file.strip_suffix(".rs"),
+            [name, "main.rs"] => Some(name),
+            _ => None,
+        };
+        if let Some(name) = name {
+            let root = Path::new(target_dir).join(rel);
+            targets.push((name.to_string(), root.display().to_string()));
+        }
+    }
+    targets
+}
+
+/// Resolve `.` and `..` in a path relative to the repository, as manifests
+/// may point outside of their package.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[test]
+fn test_locate() {
+    let manifests = [
+        (
+            "Cargo.toml".to_string(),
+            "[workspace]\nmembers = [\"crates/*\"]\n".to_string(),
+        ),
+        (
+            "crates/app/Cargo.toml".to_string(),
+            "[package]\nname = \"my-app\"\n\n[[bin]]\nname = \"tool\"\npath = \"tools/tool.rs\"\n"
+                .to_string(),
+        ),
+        (
+            "crates/derive/Cargo.toml".to_string(),
+            "[package]\nname = \"derive\"\nbuild = \"gen.rs\"\n[lib]\nproc-macro = true\n"
+                .to_string(),
+        ),
+    ];
+    let files = [
+        "crates/app/build.rs",
+        "crates/app/src/lib.rs",
+        "crates/app/src/parser.rs",
+        "crates/app/src/main.rs",
+        "crates/app/src/bin/other/main.rs",
+        "crates/app/src/bin/other/args.rs",
+        "crates/app/tools/tool.rs",
+        "crates/app/tests/common/mod.rs",
+        "crates/app/tests/it.rs",
+        "crates/app/examples/demo.rs",
+        "crates/app/benches_disabled/bilock.rs",
+        "crates/derive/gen.rs",
+        "crates/derive/src/lib.rs",
+        "scripts/check.rs",
+    ];
+    let workspace = Workspace::new(&manifests, files);
+    let locate = |file_name| {
+        let file = workspace.locate(file_name);
+        (file.package, file.target_kind, file.target_name)
+    };
+    let target = |package: &str, kind, name: &str| {
+        (
+            Some(package.to_string()),
+            Some(kind),
+            Some(name.to_string()),
+        )
+    };
+    assert_eq!(
+        locate("crates/app/src/lib.rs"),
+        target("my-app", TargetKind::Lib, "my_app")
+    );
+    assert_eq!(
+        locate("crates/app/src/parser.rs"),
+        target("my-app", TargetKind::Lib, "my_app")
+    );
+    assert_eq!(
+        locate("crates/app/src/main.rs"),
+        target("my-app", TargetKind::Bin, "my-app")
+    );
+    assert_eq!(
+        locate("crates/app/src/bin/other/args.rs"),
+        target("my-app", TargetKind::Bin, "other")
+    );
+    assert_eq!(
+        locate("crates/app/tools/tool.rs"),
+        target("my-app", TargetKind::Bin, "tool")
+    );
+    assert_eq!(
+        locate("crates/app/tests/common/mod.rs"),
+        target("my-app", TargetKind::Test, "it")
+    );
+    assert_eq!(
+        locate("crates/app/tests/it.rs"),
+        target("my-app", TargetKind::Test, "it")
+    );
+    assert_eq!(
+        locate("crates/app/examples/demo.rs"),
+        target("my-app", TargetKind::Example, "demo")
+    );
+    assert_eq!(
+        locate("crates/app/build.rs"),
+        target("my-app", TargetKind::BuildScript, "build")
+    );
+    assert_eq!(
+        locate("crates/derive/src/lib.rs"),
+        target("derive", TargetKind::ProcMacro, "derive")
+    );
+    assert_eq!(
+        locate("crates/derive/gen.rs"),
+        target("derive", TargetKind::BuildScript, "build")
+    );
+    // Not below any target root, even though the package has a build script
+    assert_eq!(
+        locate("crates/app/benches_disabled/bilock.rs"),
+        (Some("my-app".to_string()), None, None)
+    );
+    assert_eq!(locate("scripts/check.rs"), (None, None, None));
+}
+
+#[test]
+fn test_manifest_metadata() {
+    let manifests = [
+        (
+            "Cargo.toml".to_string(),
+            r#"
+[workspace]
+members = ["macros"]
+
+[workspace.package]
+edition = "2021"
+rust-version = "1.64"
+
+[workspace.dependencies]
+syn = { version = "1", features = ["full"] }
+"#
+            .to_string(),
+        ),
+        (
+            "macros/Cargo.toml".to_string(),
+            r#"
+[package]
+name = "macros"
+edition.workspace = true
+rust-version = { workspace = true }
+
+[lib]
+proc-macro = true
+
+[features]
+default = ["std"]
+std = []
+nightly = ["dep:quote", "std"]
+
+[dependencies]
+syn = { workspace = true, optional = true }
+quote = { version = "1.0", optional = true }
+pm2 = { package = "proc-macro2", git = "https://github.com/dtolnay/proc-macro2" }
+
+[dev-dependencies]
+trybuild = "1.0.49"
+
+[target.'cfg(windows)'.build-dependencies]
+winres = { path = "../winres" }
+"#
+            .to_string(),
+        ),
+    ];
+    let workspace = Workspace::new(&manifests, ["macros/src/lib.rs"]);
+    let package = &workspace.packages[0];
+    assert_eq!(package.manifest_name, "macros/Cargo.toml");
+    assert_eq!(
+        package.manifest,
+        PackageManifest {
+            package: "macros".to_string(),
+            edition: "2021".to_string(),
+            rust_version: Some("1.64".to_string()),
+            proc_macro: true,
+        }
+    );
+    let features: Vec<_> = package
+        .features
+        .iter()
+        .map(|f| (f.feature.as_str(), f.enables.as_str()))
+        .collect();
+    assert_eq!(
+        features,
+        [
+            ("default", "std"),
+            ("nightly", "dep:quote, std"),
+            ("std", "")
+        ]
+    );
+    let dependencies: Vec<_> = package
+        .dependencies
+        .iter()
+        .map(|d| {
+            (
+                d.dependency.as_str(),
+                d.kind,
+                d.version_req.as_deref(),
+                d.source,
+                d.optional,
+                d.platform.as_deref(),
+            )
+        })
+        .collect();
+    use {DependencyKind::*, DependencySource::*};
+    assert_eq!(
+        dependencies,
+        [
+            ("proc-macro2", Normal, None, Git, false, None),
+            ("quote", Normal, Some("1.0"), Registry, true, None),
+            ("syn", Normal, Some("1"), Registry, true, None),
+            ("trybuild", Dev, Some("1.0.49"), Registry, false, None),
+            ("winres", Build, None, Path, false, Some("cfg(windows)")),
+        ]
+    );
+}